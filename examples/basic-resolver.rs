@@ -1,5 +1,5 @@
 /// Resolve a single domain from the command line
-use dnsvisor::resolver::Resolver;
+use dnsvisor::config::ResolverConfig;
 use dnsvisor::rr_fields::Type;
 use std::env;
 use std::process;
@@ -9,7 +9,10 @@ fn main() {
     let domain_name = &args[1];
     env_logger::builder().format_timestamp(None).init();
     println!("Looking up domain: {}", domain_name);
-    let mut resolver = Resolver::default();
+    let resolver = ResolverConfig::new().build().unwrap_or_else(|err| {
+        println!("Invalid configuration: {:?}", err);
+        process::exit(1);
+    });
     match resolver.resolve(domain_name, Type::A) {
         Ok(ip) => {
             println!("Domain IP: {}", ip);
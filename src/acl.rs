@@ -0,0 +1,142 @@
+use crate::cidr::{Cidr, CidrTable};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// Ranges allowed by default: loopback, private, shared (CGNAT), link-local and unique local
+const PRIVATE_RANGES: [(IpAddr, u8); 9] = [
+    (IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)), 8),
+    (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8),
+    (IpAddr::V4(Ipv4Addr::new(172, 16, 0, 0)), 12),
+    (IpAddr::V4(Ipv4Addr::new(192, 168, 0, 0)), 16),
+    (IpAddr::V4(Ipv4Addr::new(100, 64, 0, 0)), 10),
+    (IpAddr::V4(Ipv4Addr::new(169, 254, 0, 0)), 16),
+    (IpAddr::V6(Ipv6Addr::LOCALHOST), 128),
+    (IpAddr::V6(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0)), 7),
+    (IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0)), 10),
+];
+
+/// What to do with a query from a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclAction {
+    Allow,
+    /// Drop the query without answering
+    Deny,
+    /// Answer REFUSED
+    Refuse,
+    /// Answer from local records, and REFUSED for anything which needs resolving
+    AllowLocalOnly,
+}
+
+impl FromStr for AclAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(AclAction::Allow),
+            "deny" => Ok(AclAction::Deny),
+            "refuse" => Ok(AclAction::Refuse),
+            "allow-local-only" => Ok(AclAction::AllowLocalOnly),
+            _ => Err(format!(
+                "Invalid ACL action {}, expected allow, deny, refuse or allow-local-only",
+                s
+            )),
+        }
+    }
+}
+
+/// Which clients may query the server, by the most specific block containing their address
+#[derive(Debug, Clone, PartialEq)]
+pub struct Acl {
+    rules: CidrTable<AclAction>,
+    // For clients outside every block
+    default: AclAction,
+}
+
+impl Default for Acl {
+    /// Allow loopback and private ranges, and refuse everyone else, so the server isn't an open
+    /// resolver when it's reachable from the internet
+    fn default() -> Self {
+        let mut acl = Self::new(AclAction::Refuse);
+        for (addr, prefix_len) in PRIVATE_RANGES {
+            if let Some(cidr) = Cidr::new(addr, prefix_len) {
+                acl.add(cidr, AclAction::Allow);
+            }
+        }
+        acl
+    }
+}
+
+impl Acl {
+    /// No rules, so every client gets `default`
+    pub fn new(default: AclAction) -> Self {
+        Self {
+            rules: CidrTable::new(),
+            default,
+        }
+    }
+
+    /// Add a rule, replacing any existing rule for the same block
+    pub fn add(&mut self, cidr: Cidr, action: AclAction) {
+        self.rules.add(cidr, action);
+    }
+
+    pub fn with_rule(mut self, cidr: Cidr, action: AclAction) -> Self {
+        self.add(cidr, action);
+        self
+    }
+
+    /// The action for clients outside every block
+    pub fn with_default(mut self, default: AclAction) -> Self {
+        self.default = default;
+        self
+    }
+
+    /// The action for the rule with the longest prefix containing `ip`
+    pub fn action_for(&self, ip: IpAddr) -> AclAction {
+        self.rules.lookup(ip).copied().unwrap_or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn default_allows_private_ranges() {
+        let acl = Acl::default();
+        for client in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.1.20",
+            "::1",
+            "fd12::1",
+            "::ffff:10.0.0.1",
+        ] {
+            assert_eq!(acl.action_for(ip(client)), AclAction::Allow, "{}", client);
+        }
+        assert_eq!(acl.action_for(ip("203.0.113.1")), AclAction::Refuse);
+        assert_eq!(acl.action_for(ip("2001:db8::1")), AclAction::Refuse);
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let acl = Acl::default()
+            .with_rule("192.168.1.0/24".parse().unwrap(), AclAction::Deny)
+            .with_rule("192.168.1.50".parse().unwrap(), AclAction::AllowLocalOnly)
+            .with_rule("10.0.0.0/8".parse().unwrap(), AclAction::Refuse);
+        assert_eq!(acl.action_for(ip("192.168.2.1")), AclAction::Allow);
+        assert_eq!(acl.action_for(ip("192.168.1.1")), AclAction::Deny);
+        assert_eq!(
+            acl.action_for(ip("192.168.1.50")),
+            AclAction::AllowLocalOnly
+        );
+        assert_eq!(acl.action_for(ip("10.0.0.1")), AclAction::Refuse);
+        let open = Acl::new(AclAction::Allow);
+        assert_eq!(open.action_for(ip("203.0.113.1")), AclAction::Allow);
+    }
+}
@@ -0,0 +1,50 @@
+use crate::error::DnsError;
+use crate::packet::DnsPacket;
+use crate::resolver::Resolver;
+use crate::rr_fields::Type;
+use crate::trace::Trace;
+use crate::transport::TokioTransport;
+use std::sync::Arc;
+
+/// A `Resolver` for tokio applications, which sends queries on the caller's runtime instead of
+/// blocking a thread. It shares the cache, blocklist and every other behaviour of the
+/// synchronous resolver it's built from.
+///
+/// Lookups are cancellation safe: dropping one part way through abandons its outstanding query,
+/// and the caches only ever hold complete responses.
+pub struct AsyncResolver {
+    resolver: Resolver,
+}
+
+impl AsyncResolver {
+    /// Resolve asynchronously with the settings of `resolver`
+    pub fn new(resolver: Resolver) -> Self {
+        Self {
+            resolver: resolver.with_transport(Arc::new(TokioTransport)),
+        }
+    }
+
+    /// See `Resolver::prime`
+    pub async fn prime(&self) -> Result<(), DnsError> {
+        self.resolver.prime_async().await
+    }
+
+    pub async fn resolve_packet(&self, query_packet: DnsPacket) -> Result<DnsPacket, DnsError> {
+        self.resolver.resolve_packet_async(query_packet).await
+    }
+
+    pub async fn resolve(&self, domain_name: &str, record_type: Type) -> Result<String, DnsError> {
+        self.resolver.resolve_async(domain_name, record_type).await
+    }
+
+    /// See `Resolver::trace`
+    pub async fn trace(&self, domain_name: &str, record_type: Type) -> Trace {
+        self.resolver.trace_async(domain_name, record_type).await
+    }
+}
+
+impl Default for AsyncResolver {
+    fn default() -> Self {
+        Self::new(Resolver::default())
+    }
+}
@@ -1,31 +1,81 @@
+use crate::clock::{Clock, SystemClock};
+use crate::delegation::Delegation;
 use crate::error::DnsError;
 use crate::packet::DnsPacket;
 use crate::question::DnsQuestion;
 use crate::record::DnsRecord;
 use crate::rr_fields::Type;
-use log::debug;
-use std::collections::HashMap;
+use crate::util::normalize_name;
+use log::{debug, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, ErrorKind, Write};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+/// First line of a cache snapshot, followed by the Unix time it was saved at
+const SNAPSHOT_HEADER: &str = "# dnsvisor cache snapshot saved at";
+
+/// Bounds on what the answer cache holds
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheLimits {
+    /// Records kept at once. When full, the record which expires soonest is dropped, so expired
+    /// records go first.
+    pub max_entries: usize,
+    /// TTLs are raised to at least this many seconds
+    pub min_ttl: u32,
+    /// TTLs are lowered to at most this many seconds
+    pub max_ttl: u32,
+}
+
+impl Default for CacheLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: 100_000,
+            min_ttl: 0,
+            max_ttl: 86400,
+        }
+    }
+}
+
 pub struct DnsCache {
     // The key of a DnsCache row is the fields of a DnsQuestion
     // TODO switch key to u64 hash? (check for performance difference)
     cache: HashMap<DnsQuestion, DnsCacheEntry>,
+    // Questions in order of expiry, so a full cache can evict without scanning every entry
+    expiry: BTreeMap<(Instant, u64), DnsQuestion>,
+    // Tells apart entries which expire at the same instant
+    next_id: u64,
+    clock: Arc<dyn Clock>,
+    limits: CacheLimits,
 }
 
 impl DnsCache {
     pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
         Self {
             cache: HashMap::new(),
+            expiry: BTreeMap::new(),
+            next_id: 0,
+            clock,
+            limits: CacheLimits::default(),
         }
     }
 
+    pub fn with_limits(mut self, limits: CacheLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
     pub fn lookup(&mut self, question: &DnsQuestion) -> Option<&DnsRecord> {
         // Delete cache entry if expired
+        let now = self.clock.now();
         if let Some(entry) = self.cache.get(question) {
-            if entry.expired() {
+            if entry.expired(now) {
                 debug!("Expired cache entry");
-                self.cache.remove(question);
+                self.remove(question);
             }
         }
         self.cache.get(question).map(|x| &x.record)
@@ -33,10 +83,98 @@ impl DnsCache {
 
     pub fn add(&mut self, record: &DnsRecord) -> Result<(), DnsError> {
         let question = record.get_question();
-        let entry = DnsCacheEntry::new(record.clone())?;
-        self.cache.insert(question, entry);
+        let mut record = record.clone();
+        record.ttl = record.ttl.clamp(self.limits.min_ttl, self.limits.max_ttl);
+        let mut entry = DnsCacheEntry::new(record, self.clock.now())?;
+        entry.id = self.next_id;
+        self.next_id += 1;
+        if !self.cache.contains_key(&question) {
+            self.make_room();
+        }
+        self.expiry.insert(entry.expiry_key(), question.clone());
+        if let Some(replaced) = self.cache.insert(question, entry) {
+            self.expiry.remove(&replaced.expiry_key());
+        }
         Ok(())
     }
+
+    fn remove(&mut self, question: &DnsQuestion) {
+        if let Some(entry) = self.cache.remove(question) {
+            self.expiry.remove(&entry.expiry_key());
+        }
+    }
+
+    /// Evict the entries which expire soonest until there is space for one more
+    fn make_room(&mut self) {
+        while self.cache.len() >= self.limits.max_entries {
+            let Some((_, question)) = self.expiry.pop_first() else {
+                break;
+            };
+            self.cache.remove(&question);
+        }
+    }
+
+    /// Unexpired records, with TTLs lowered to the whole seconds they have left
+    pub fn records(&self) -> Vec<DnsRecord> {
+        let now = self.clock.now();
+        self.cache
+            .values()
+            .filter_map(|entry| {
+                let ttl = u32::try_from(entry.expires.saturating_duration_since(now).as_secs())
+                    .unwrap_or(u32::MAX);
+                let mut record = entry.record.clone();
+                record.ttl = ttl;
+                (ttl > 0).then_some(record)
+            })
+            .collect()
+    }
+
+    /// Write the unexpired records one per line in zone file layout, after a header with
+    /// `unix_time`. Returns how many records were written.
+    pub fn write_snapshot(&self, mut writer: impl Write, unix_time: u64) -> io::Result<usize> {
+        let records = self.records();
+        writeln!(writer, "{} {}", SNAPSHOT_HEADER, unix_time)?;
+        for record in &records {
+            writeln!(writer, "{}", record)?;
+        }
+        writer.flush()?;
+        Ok(records.len())
+    }
+
+    /// Add the records from a snapshot, less the time between when it was saved and
+    /// `unix_time`. Returns how many records were still live.
+    pub fn read_snapshot(&mut self, reader: impl BufRead, unix_time: u64) -> io::Result<usize> {
+        let mut lines = reader.lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        let saved_at = header
+            .strip_prefix(SNAPSHOT_HEADER)
+            .and_then(|saved_at| saved_at.trim().parse::<u64>().ok())
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Not a cache snapshot"))?;
+        let age = unix_time.saturating_sub(saved_at);
+        let mut loaded = 0;
+        for line in lines {
+            let line = line?;
+            let mut record: DnsRecord = match line.parse() {
+                Ok(record) => record,
+                Err(_) => {
+                    warn!("Skipping invalid cache snapshot record: {}", line);
+                    continue;
+                }
+            };
+            let Some(ttl) = u64::from(record.ttl)
+                .checked_sub(age)
+                .filter(|ttl| *ttl > 0)
+            else {
+                continue;
+            };
+            record.ttl = ttl as u32;
+            if self.add(&record).is_ok() {
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
     pub fn cache_answers(&mut self, packet: &DnsPacket) -> Result<(), DnsError> {
         for answer in &packet.answers {
             if Self::should_cache(answer) {
@@ -63,14 +201,19 @@ impl Default for DnsCache {
 struct DnsCacheEntry {
     record: DnsRecord,
     expires: Instant,
+    // Assigned by the cache, to order entries which expire at the same instant
+    id: u64,
 }
 
 impl DnsCacheEntry {
-    pub fn new(record: DnsRecord) -> Result<Self, DnsError> {
-        let now = Instant::now();
+    pub fn new(record: DnsRecord, now: Instant) -> Result<Self, DnsError> {
         let ttl_duration = Duration::from_secs(record.ttl as u64);
         if let Some(expires) = now.checked_add(ttl_duration) {
-            Ok(Self { record, expires })
+            Ok(Self {
+                record,
+                expires,
+                id: 0,
+            })
         } else {
             Err(DnsError::CacheError(
                 "Failed to create expiration time for cache record",
@@ -78,19 +221,91 @@ impl DnsCacheEntry {
         }
     }
 
-    fn expired(&self) -> bool {
-        let now = Instant::now();
+    fn expired(&self, now: Instant) -> bool {
         now >= self.expires
     }
+
+    fn expiry_key(&self) -> (Instant, u64) {
+        (self.expires, self.id)
+    }
+}
+
+/// Delegations learned from referrals, keyed by zone, so resolution can start from the closest
+/// known zone instead of the root
+pub struct DelegationCache {
+    delegations: HashMap<String, DelegationCacheEntry>,
+    clock: Arc<dyn Clock>,
+}
+
+impl DelegationCache {
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            delegations: HashMap::new(),
+            clock,
+        }
+    }
+
+    pub fn add(&mut self, delegation: &Delegation) -> Result<(), DnsError> {
+        let ttl_duration = Duration::from_secs(delegation.ttl as u64);
+        let expires = self
+            .clock
+            .now()
+            .checked_add(ttl_duration)
+            .ok_or(DnsError::CacheError(
+                "Failed to create expiration time for cached delegation",
+            ))?;
+        let entry = DelegationCacheEntry {
+            delegation: delegation.clone(),
+            expires,
+        };
+        self.delegations.insert(delegation.zone.clone(), entry);
+        Ok(())
+    }
+
+    /// Find the cached delegation for the closest zone enclosing `domain_name`
+    pub fn closest(&mut self, domain_name: &str) -> Option<&Delegation> {
+        let now = self.clock.now();
+        let domain_name = normalize_name(domain_name);
+        let mut zone = domain_name.as_str();
+        let found = loop {
+            if let Some(entry) = self.delegations.get(zone) {
+                if now < entry.expires {
+                    break Some(zone.to_string());
+                }
+                debug!("Expired delegation for {}", zone);
+                self.delegations.remove(zone);
+            }
+            match zone.split_once('.') {
+                Some((_label, parent)) => zone = parent,
+                None => break None,
+            }
+        };
+        found.and_then(|zone| self.delegations.get(&zone).map(|entry| &entry.delegation))
+    }
+}
+
+impl Default for DelegationCache {
+    fn default() -> Self {
+        DelegationCache::new()
+    }
+}
+
+struct DelegationCacheEntry {
+    delegation: Delegation,
+    expires: Instant,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::MockClock;
     use crate::record::Rdata;
     use crate::rr_fields::*;
     use pretty_assertions::assert_eq;
-    use std::thread;
     #[test]
     fn entry_not_expired() {
         let ttl = 5;
@@ -98,35 +313,34 @@ mod tests {
         let record = DnsRecord {
             name: String::from("placeholder"),
             class: Class::CLASS_IN,
-            ttl: ttl,
+            ttl,
             rdata: Rdata::A(String::from("")),
         };
-        let entry = DnsCacheEntry::new(record).unwrap();
-        let res = entry.expired();
+        let now = Instant::now();
+        let entry = DnsCacheEntry::new(record, now).unwrap();
+        let res = entry.expired(now);
         assert_eq!(expected, res);
     }
     #[test]
     fn entry_expired() {
         let ttl: u32 = 1;
-        let sleep_time = Duration::from_secs((ttl + 1) as u64);
+        let elapsed = Duration::from_secs((ttl + 1) as u64);
         let expected = true;
         let record = DnsRecord {
             name: String::from("example.com"),
             class: Class::CLASS_IN,
-            ttl: ttl,
+            ttl,
             rdata: Rdata::A(String::from("")),
         };
-        let entry = DnsCacheEntry::new(record);
-        thread::sleep(sleep_time);
-        let res = entry.unwrap().expired();
+        let now = Instant::now();
+        let entry = DnsCacheEntry::new(record, now);
+        let res = entry.unwrap().expired(now + elapsed);
         assert_eq!(expected, res);
     }
 
     #[test]
     fn cache_lookup_empty() {
-        let mut cache = DnsCache {
-            cache: HashMap::new(),
-        };
+        let mut cache = DnsCache::new();
         let query = DnsQuestion {
             name: "example.com".to_string(),
             qtype: Type::A,
@@ -137,9 +351,7 @@ mod tests {
     }
     #[test]
     fn cache_lookup_hit() {
-        let mut cache = DnsCache {
-            cache: HashMap::new(),
-        };
+        let mut cache = DnsCache::new();
         let record = DnsRecord {
             name: "example.com".to_string(),
             class: Class::CLASS_IN,
@@ -154,9 +366,7 @@ mod tests {
     }
     #[test]
     fn cache_lookup_expired() {
-        let mut cache = DnsCache {
-            cache: HashMap::new(),
-        };
+        let mut cache = DnsCache::new();
         let record = DnsRecord {
             name: "example.com".to_string(),
             class: Class::CLASS_IN,
@@ -168,6 +378,152 @@ mod tests {
         let expected = None;
         let result = cache.lookup(&question);
         assert_eq!(result, expected);
-        assert!(cache.cache.is_empty())
+        assert!(cache.cache.is_empty());
+        assert!(cache.expiry.is_empty());
+    }
+    #[test]
+    fn cache_lookup_expires_with_clock() {
+        let clock = MockClock::new();
+        let mut cache = DnsCache::with_clock(Arc::new(clock.clone()));
+        let record = DnsRecord {
+            name: "example.com".to_string(),
+            class: Class::CLASS_IN,
+            ttl: 300,
+            rdata: Rdata::A("127.0.0.1".to_string()),
+        };
+        let question = record.get_question();
+        cache.add(&record).unwrap();
+        clock.advance(Duration::from_secs(299));
+        assert_eq!(cache.lookup(&question), Some(&record));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.lookup(&question), None);
+    }
+    #[test]
+    fn cache_limits() {
+        let limits = CacheLimits {
+            max_entries: 2,
+            min_ttl: 60,
+            max_ttl: 3600,
+        };
+        let mut cache = DnsCache::new().with_limits(limits);
+        let record = |name: &str, ttl| DnsRecord {
+            name: name.to_string(),
+            class: Class::CLASS_IN,
+            ttl,
+            rdata: Rdata::A("127.0.0.1".to_string()),
+        };
+        cache.add(&record("short.example.com", 1)).unwrap();
+        cache.add(&record("long.example.com", 86400)).unwrap();
+        cache.add(&record("new.example.com", 300)).unwrap();
+        let ttl = |cache: &mut DnsCache, name| {
+            let question = record(name, 0).get_question();
+            cache.lookup(&question).map(|record| record.ttl)
+        };
+        assert_eq!(ttl(&mut cache, "short.example.com"), None);
+        assert_eq!(ttl(&mut cache, "long.example.com"), Some(3600));
+        assert_eq!(ttl(&mut cache, "new.example.com"), Some(300));
+    }
+    #[test]
+    fn full_cache_evicts_soonest_expiry() {
+        let clock = MockClock::new();
+        let limits = CacheLimits {
+            max_entries: 3,
+            ..CacheLimits::default()
+        };
+        let mut cache = DnsCache::with_clock(Arc::new(clock.clone())).with_limits(limits);
+        let record = |name: &str, ttl| DnsRecord {
+            name: name.to_string(),
+            class: Class::CLASS_IN,
+            ttl,
+            rdata: Rdata::A("192.0.2.1".to_string()),
+        };
+        let cached = |cache: &DnsCache| {
+            let mut names: Vec<String> = cache.cache.keys().map(|q| q.name.clone()).collect();
+            names.sort();
+            names
+        };
+        cache.add(&record("a.example.com", 300)).unwrap();
+        cache.add(&record("b.example.com", 100)).unwrap();
+        cache.add(&record("c.example.com", 200)).unwrap();
+        cache.add(&record("d.example.com", 400)).unwrap();
+        assert_eq!(
+            cached(&cache),
+            vec!["a.example.com", "c.example.com", "d.example.com"]
+        );
+        // Replacing a record doesn't evict anything, and moves it in the expiry order
+        cache.add(&record("a.example.com", 50)).unwrap();
+        cache.add(&record("e.example.com", 500)).unwrap();
+        assert_eq!(
+            cached(&cache),
+            vec!["c.example.com", "d.example.com", "e.example.com"]
+        );
+        clock.advance(Duration::from_secs(250));
+        cache.add(&record("f.example.com", 100)).unwrap();
+        assert_eq!(
+            cached(&cache),
+            vec!["d.example.com", "e.example.com", "f.example.com"]
+        );
+        assert_eq!(cache.cache.len(), 3);
+        assert_eq!(cache.expiry.len(), 3);
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let clock = MockClock::new();
+        let mut cache = DnsCache::with_clock(Arc::new(clock.clone()));
+        let record = |name: &str, ttl| DnsRecord {
+            name: name.to_string(),
+            class: Class::CLASS_IN,
+            ttl,
+            rdata: Rdata::A("192.0.2.1".to_string()),
+        };
+        cache.add(&record("short.example.com", 30)).unwrap();
+        cache.add(&record("long.example.com", 300)).unwrap();
+        clock.advance(Duration::from_secs(10));
+        let mut snapshot = vec![];
+        assert_eq!(cache.write_snapshot(&mut snapshot, 1000).unwrap(), 2);
+
+        // Restored 60 seconds after it was saved
+        let mut restored = DnsCache::new();
+        assert_eq!(restored.read_snapshot(&snapshot[..], 1060).unwrap(), 1);
+        let question = record("long.example.com", 0).get_question();
+        assert_eq!(
+            restored.lookup(&question),
+            Some(&record("long.example.com", 230))
+        );
+        let question = record("short.example.com", 0).get_question();
+        assert_eq!(restored.lookup(&question), None);
+        assert!(restored.read_snapshot(&b"example.com"[..], 0).is_err());
+    }
+
+    fn delegation(zone: &str, ttl: u32) -> Delegation {
+        Delegation {
+            zone: zone.to_string(),
+            nameservers: vec![],
+            ttl,
+        }
+    }
+    #[test]
+    fn delegation_closest_enclosing_zone() {
+        let mut cache = DelegationCache::new();
+        cache.add(&delegation("com", 300)).unwrap();
+        cache.add(&delegation("example.com", 300)).unwrap();
+        let res = cache.closest("b.Example.com").map(|d| d.zone.clone());
+        assert_eq!(res, Some("example.com".to_string()));
+        let res = cache.closest("example.org").map(|d| d.zone.clone());
+        assert_eq!(res, None);
+        let res = cache.closest("other.com").map(|d| d.zone.clone());
+        assert_eq!(res, Some("com".to_string()));
+    }
+    #[test]
+    fn delegation_expires() {
+        let clock = MockClock::new();
+        let mut cache = DelegationCache::with_clock(Arc::new(clock.clone()));
+        cache.add(&delegation("com", 600)).unwrap();
+        cache.add(&delegation("example.com", 60)).unwrap();
+        clock.advance(Duration::from_secs(60));
+        let res = cache.closest("www.example.com").map(|d| d.zone.clone());
+        assert_eq!(res, Some("com".to_string()));
+        assert!(!cache.delegations.contains_key("example.com"));
     }
 }
@@ -0,0 +1,164 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// A block of addresses like `192.168.0.0/16`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    // With the bits past the prefix cleared
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+fn mask_v4(ip: Ipv4Addr, prefix_len: u8) -> Ipv4Addr {
+    let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+    Ipv4Addr::from(u32::from(ip) & mask)
+}
+
+fn mask_v6(ip: Ipv6Addr, prefix_len: u8) -> Ipv6Addr {
+    let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+    Ipv6Addr::from(u128::from(ip) & mask)
+}
+
+impl Cidr {
+    /// The block of `prefix_len` leading bits of `addr`, or None when `prefix_len` is too long.
+    /// IPv4-mapped IPv6 blocks are treated as IPv4.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        match addr {
+            IpAddr::V6(v6) if v6.to_ipv4_mapped().is_some() && prefix_len >= 96 => {
+                Self::new(IpAddr::V4(v6.to_ipv4_mapped()?), prefix_len - 96)
+            }
+            IpAddr::V4(v4) if prefix_len <= 32 => Some(Self {
+                addr: IpAddr::V4(mask_v4(v4, prefix_len)),
+                prefix_len,
+            }),
+            IpAddr::V6(v6) if prefix_len <= 128 => Some(Self {
+                addr: IpAddr::V6(mask_v6(v6, prefix_len)),
+                prefix_len,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether `ip` is in this block. IPv4 clients of a dual-stack socket arrive as IPv4-mapped
+    /// IPv6 addresses, so those match IPv4 blocks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(block), IpAddr::V4(ip)) => mask_v4(ip, self.prefix_len) == block,
+            (IpAddr::V6(block), IpAddr::V6(ip)) => mask_v6(ip, self.prefix_len) == block,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    /// Parse `IP/PREFIX`, or a bare IP as a block of one address
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid CIDR block {}, expected e.g. 192.168.0.0/16", s);
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => {
+                let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+                (addr, prefix_len.parse::<u8>().map_err(|_| invalid())?)
+            }
+            None => {
+                let addr: IpAddr = s.parse().map_err(|_| invalid())?;
+                (addr, if addr.is_ipv4() { 32 } else { 128 })
+            }
+        };
+        Self::new(addr, prefix_len).ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Values for blocks of addresses, looked up by the most specific block containing an address
+#[derive(Debug, Clone, PartialEq)]
+pub struct CidrTable<T> {
+    entries: Vec<(Cidr, T)>,
+}
+
+impl<T> Default for CidrTable<T> {
+    fn default() -> Self {
+        Self { entries: vec![] }
+    }
+}
+
+impl<T> CidrTable<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an entry, replacing any existing entry for the same block
+    pub fn add(&mut self, cidr: Cidr, value: T) {
+        self.entries.retain(|(entry_cidr, _)| *entry_cidr != cidr);
+        self.entries.push((cidr, value));
+    }
+
+    /// The value for the entry with the longest prefix containing `ip`
+    pub fn lookup(&self, ip: IpAddr) -> Option<&T> {
+        self.entries
+            .iter()
+            .filter(|(cidr, _)| cidr.contains(ip))
+            .max_by_key(|(cidr, _)| cidr.prefix_len())
+            .map(|(_, value)| value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_cidr() {
+        let cidr: Cidr = "192.168.1.77/24".parse().unwrap();
+        assert_eq!(cidr.to_string(), "192.168.1.0/24");
+        assert_eq!(
+            "10.0.0.1".parse::<Cidr>().unwrap().to_string(),
+            "10.0.0.1/32"
+        );
+        assert_eq!("fd00::1/8".parse::<Cidr>().unwrap().to_string(), "fd00::/8");
+        assert_eq!(
+            "::ffff:10.0.0.0/104".parse::<Cidr>().unwrap().to_string(),
+            "10.0.0.0/8"
+        );
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn contains_mapped_addresses() {
+        let cidr: Cidr = "192.168.0.0/16".parse().unwrap();
+        assert!(cidr.contains(ip("192.168.4.2")));
+        assert!(cidr.contains(ip("::ffff:192.168.4.2")));
+        assert!(!cidr.contains(ip("192.169.0.1")));
+        assert!(!cidr.contains(ip("fd00::1")));
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("203.0.113.1")));
+    }
+
+    #[test]
+    fn table_lookup_longest_prefix() {
+        let mut table = CidrTable::new();
+        table.add("10.0.0.0/8".parse().unwrap(), "wide");
+        table.add("10.1.0.0/16".parse().unwrap(), "narrow");
+        table.add("10.0.0.0/8".parse().unwrap(), "replaced");
+        assert_eq!(table.lookup(ip("10.1.2.3")), Some(&"narrow"));
+        assert_eq!(table.lookup(ip("10.2.0.1")), Some(&"replaced"));
+        assert_eq!(table.lookup(ip("192.0.2.1")), None);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::error::DnsError;
+use crate::packet::DnsPacket;
+use crate::question::DnsQuestion;
+use crate::util::lock;
+use std::collections::HashMap;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+type FlightResult = Result<DnsPacket, DnsError>;
+
+enum FlightState {
+    Pending(Vec<Waker>),
+    Done(FlightResult),
+    /// The leader was dropped before finishing, so a follower has to take over
+    Abandoned,
+}
+
+/// One resolution which other requests for the same question wait on
+pub struct Flight {
+    state: Mutex<FlightState>,
+}
+
+impl Flight {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(FlightState::Pending(vec![])),
+        }
+    }
+
+    fn land(&self, state: FlightState) {
+        let previous = mem::replace(&mut *lock(&self.state), state);
+        if let FlightState::Pending(wakers) = previous {
+            wakers.into_iter().for_each(Waker::wake);
+        }
+    }
+
+    /// Wait for the leader's result, or None if the leader gave up
+    pub fn wait(self: Arc<Self>) -> FlightWait {
+        FlightWait { flight: self }
+    }
+}
+
+pub struct FlightWait {
+    flight: Arc<Flight>,
+}
+
+impl Future for FlightWait {
+    type Output = Option<FlightResult>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        match &mut *lock(&self.flight.state) {
+            FlightState::Pending(wakers) => {
+                if !wakers.iter().any(|waker| waker.will_wake(context.waker())) {
+                    wakers.push(context.waker().clone());
+                }
+                Poll::Pending
+            }
+            FlightState::Done(result) => Poll::Ready(Some(result.clone())),
+            FlightState::Abandoned => Poll::Ready(None),
+        }
+    }
+}
+
+/// The role of a request in resolving its question
+pub enum Joined<'a> {
+    /// No identical request is in flight, so this one resolves and shares the result
+    Leader(LeaderGuard<'a>),
+    Follower(Arc<Flight>),
+}
+
+/// Questions currently being resolved, so identical concurrent requests share one resolution
+pub struct InFlight {
+    flights: Mutex<HashMap<DnsQuestion, Arc<Flight>>>,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self {
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn join(&self, question: DnsQuestion) -> Joined<'_> {
+        let mut flights = lock(&self.flights);
+        if let Some(flight) = flights.get(&question) {
+            return Joined::Follower(flight.clone());
+        }
+        let flight = Arc::new(Flight::new());
+        flights.insert(question.clone(), flight.clone());
+        Joined::Leader(LeaderGuard {
+            in_flight: self,
+            question,
+            flight,
+            finished: false,
+        })
+    }
+}
+
+/// Held by the request resolving a question. Followers are released when it finishes, or are
+/// told to retry if it's dropped first, e.g. because an async lookup was cancelled.
+pub struct LeaderGuard<'a> {
+    in_flight: &'a InFlight,
+    question: DnsQuestion,
+    flight: Arc<Flight>,
+    finished: bool,
+}
+
+impl LeaderGuard<'_> {
+    pub fn finish(mut self, result: &FlightResult) {
+        self.finished = true;
+        self.land(FlightState::Done(result.clone()));
+    }
+
+    fn land(&self, state: FlightState) {
+        // Later requests start afresh, normally answered from the cache
+        lock(&self.in_flight.flights).remove(&self.question);
+        self.flight.land(state);
+    }
+}
+
+impl Drop for LeaderGuard<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.land(FlightState::Abandoned);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rr_fields::{Class, Type};
+    use crate::util::block_on;
+    use pretty_assertions::assert_eq;
+    use std::thread;
+
+    fn question() -> DnsQuestion {
+        DnsQuestion::new("example.com", Type::A, Class::CLASS_IN)
+    }
+
+    #[test]
+    fn follower_gets_leader_result() {
+        let in_flight = InFlight::new();
+        let Joined::Leader(leader) = in_flight.join(question()) else {
+            panic!("first request should lead");
+        };
+        let Joined::Follower(flight) = in_flight.join(question()) else {
+            panic!("second request should follow");
+        };
+        let waiter = thread::spawn(move || block_on(flight.wait()));
+        let result = Err(DnsError::ResolveError("failed".to_string()));
+        leader.finish(&result);
+        assert_eq!(waiter.join().unwrap(), Some(result));
+        assert!(matches!(in_flight.join(question()), Joined::Leader(_)));
+    }
+
+    #[test]
+    fn dropped_leader_releases_followers() {
+        let in_flight = InFlight::new();
+        let leader = in_flight.join(question());
+        let Joined::Follower(flight) = in_flight.join(question()) else {
+            panic!("second request should follow");
+        };
+        drop(leader);
+        assert_eq!(block_on(flight.wait()), None);
+        assert!(matches!(in_flight.join(question()), Joined::Leader(_)));
+    }
+}
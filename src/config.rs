@@ -0,0 +1,313 @@
+use crate::delegation::DNS_PORT;
+use crate::error::DnsError;
+use crate::forward::ForwardZone;
+use crate::local::LocalRecords;
+use crate::resolver::{
+    Blocking, CacheLimits, IpPreference, Limits, Resolver, Timeouts, UpstreamMode,
+};
+use crate::root_hints::RootHints;
+use crate::util::normalize_name;
+use log::LevelFilter;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+
+/// Everything that configures a `Resolver`, checked as a whole when it's built so mistakes are
+/// reported up front instead of surfacing as failed lookups
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolverConfig {
+    upstream_mode: UpstreamMode,
+    forward_zones: Vec<ForwardZone>,
+    root_hints: RootHints,
+    timeouts: Timeouts,
+    limits: Limits,
+    cache_limits: CacheLimits,
+    blocklist: HashSet<String>,
+    allowlist: HashSet<String>,
+    blocking: Blocking,
+    local_records: LocalRecords,
+    ip_preference: IpPreference,
+    // Servers exempt from DNS 0x20, or None when it's disabled
+    case_randomization: Option<HashSet<IpAddr>>,
+    qname_minimisation: bool,
+    nameserver_port: u16,
+    log_level: LevelFilter,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            upstream_mode: UpstreamMode::Recursive,
+            forward_zones: vec![],
+            root_hints: RootHints::default(),
+            timeouts: Timeouts::default(),
+            limits: Limits::default(),
+            cache_limits: CacheLimits::default(),
+            blocklist: HashSet::new(),
+            allowlist: HashSet::new(),
+            blocking: Blocking::default(),
+            local_records: LocalRecords::new(),
+            ip_preference: IpPreference::default(),
+            case_randomization: None,
+            qname_minimisation: false,
+            nameserver_port: DNS_PORT,
+            log_level: LevelFilter::Error,
+        }
+    }
+}
+
+fn invalid(message: impl Into<String>) -> DnsError {
+    DnsError::ConfigError(message.into())
+}
+
+fn check_upstreams(upstreams: &[SocketAddr], context: &str) -> Result<(), DnsError> {
+    if upstreams.is_empty() {
+        return Err(invalid(format!("{} has no upstreams", context)));
+    }
+    match upstreams.iter().find(|upstream| upstream.port() == 0) {
+        Some(upstream) => Err(invalid(format!(
+            "{} upstream {} has port 0",
+            context, upstream
+        ))),
+        None => Ok(()),
+    }
+}
+
+impl ResolverConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_upstream_mode(mut self, upstream_mode: UpstreamMode) -> Self {
+        self.upstream_mode = upstream_mode;
+        self
+    }
+
+    /// Forward queries for names at or below `suffix` to `upstreams`, regardless of upstream mode
+    pub fn with_forward_zone(mut self, suffix: &str, upstreams: Vec<SocketAddr>) -> Self {
+        self.forward_zones.push(ForwardZone {
+            suffix: suffix.to_string(),
+            upstreams,
+        });
+        self
+    }
+
+    pub fn with_root_hints(mut self, root_hints: RootHints) -> Self {
+        self.root_hints = root_hints;
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_cache_limits(mut self, cache_limits: CacheLimits) -> Self {
+        self.cache_limits = cache_limits;
+        self
+    }
+
+    /// Names answered locally instead of being resolved
+    pub fn with_blocklist(mut self, blocklist: HashSet<String>) -> Self {
+        self.blocklist = blocklist;
+        self
+    }
+
+    /// Names never blocked, even when they're on the blocklist
+    pub fn with_allowlist(mut self, allowlist: HashSet<String>) -> Self {
+        self.allowlist = allowlist;
+        self
+    }
+
+    pub fn with_blocking(mut self, blocking: Blocking) -> Self {
+        self.blocking = blocking;
+        self
+    }
+
+    /// Records answered directly, ahead of the blocklist and upstreams
+    pub fn with_local_records(mut self, local_records: LocalRecords) -> Self {
+        self.local_records = local_records;
+        self
+    }
+
+    pub fn with_ip_preference(mut self, ip_preference: IpPreference) -> Self {
+        self.ip_preference = ip_preference;
+        self
+    }
+
+    /// Randomize the case of outgoing query names (DNS 0x20), except for servers in `exempt`
+    pub fn with_case_randomization(mut self, exempt: HashSet<IpAddr>) -> Self {
+        self.case_randomization = Some(exempt);
+        self
+    }
+
+    pub fn with_qname_minimisation(mut self, enabled: bool) -> Self {
+        self.qname_minimisation = enabled;
+        self
+    }
+
+    pub fn with_nameserver_port(mut self, port: u16) -> Self {
+        self.nameserver_port = port;
+        self
+    }
+
+    /// Most verbose level to log at. The library only emits through `log`, so it's up to the
+    /// application to install a logger with this level.
+    pub fn with_log_level(mut self, log_level: LevelFilter) -> Self {
+        self.log_level = log_level;
+        self
+    }
+
+    pub fn upstream_mode(&self) -> &UpstreamMode {
+        &self.upstream_mode
+    }
+
+    pub fn cache_limits(&self) -> &CacheLimits {
+        &self.cache_limits
+    }
+
+    pub fn blocking(&self) -> &Blocking {
+        &self.blocking
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        self.log_level
+    }
+
+    /// Check the options are usable together
+    pub fn validate(&self) -> Result<(), DnsError> {
+        if let UpstreamMode::Forward(upstreams) = &self.upstream_mode {
+            check_upstreams(upstreams, "Forward mode")?;
+        }
+        for zone in &self.forward_zones {
+            if normalize_name(&zone.suffix).is_empty() {
+                return Err(invalid("Forward zone suffix is empty"));
+            }
+            check_upstreams(&zone.upstreams, &format!("Forward zone {}", zone.suffix))?;
+        }
+        if self.timeouts.query.is_zero() {
+            return Err(invalid("Query timeout must be more than zero"));
+        }
+        if self.timeouts.resolution < self.timeouts.query {
+            return Err(invalid(format!(
+                "Resolution timeout {:?} is shorter than the query timeout {:?}",
+                self.timeouts.resolution, self.timeouts.query
+            )));
+        }
+        let limits = [
+            ("cname_chain", self.limits.cname_chain),
+            ("referrals", self.limits.referrals),
+            ("nameserver_depth", self.limits.nameserver_depth),
+            ("queries", self.limits.queries),
+        ];
+        if let Some((name, _)) = limits.iter().find(|(_, limit)| *limit == 0) {
+            return Err(invalid(format!("Limit {} must be more than zero", name)));
+        }
+        if self.cache_limits.max_entries == 0 {
+            return Err(invalid("Cache max_entries must be more than zero"));
+        }
+        if self.cache_limits.min_ttl > self.cache_limits.max_ttl {
+            return Err(invalid(format!(
+                "Cache min_ttl {} is above max_ttl {}",
+                self.cache_limits.min_ttl, self.cache_limits.max_ttl
+            )));
+        }
+        if self.nameserver_port == 0 {
+            return Err(invalid("Nameserver port must not be 0"));
+        }
+        Ok(())
+    }
+
+    /// Validate the options and build a resolver from them
+    pub fn build(self) -> Result<Resolver, DnsError> {
+        self.validate()?;
+        let mut resolver = Resolver::new(self.blocklist)
+            .with_allowlist(self.allowlist)
+            .with_local_records(self.local_records)
+            .with_upstream_mode(self.upstream_mode)
+            .with_root_hints(self.root_hints)
+            .with_timeouts(self.timeouts)
+            .with_limits(self.limits)
+            .with_cache_limits(self.cache_limits)
+            .with_blocking(self.blocking)
+            .with_ip_preference(self.ip_preference)
+            .with_qname_minimisation(self.qname_minimisation)
+            .with_nameserver_port(self.nameserver_port);
+        for zone in self.forward_zones {
+            resolver = resolver.with_forward_zone(&zone.suffix, zone.upstreams);
+        }
+        if let Some(exempt) = self.case_randomization {
+            resolver = resolver.with_case_randomization(exempt);
+        }
+        Ok(resolver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert_eq!(ResolverConfig::new().validate(), Ok(()));
+    }
+
+    #[test]
+    fn forward_mode_needs_upstreams() {
+        let config = ResolverConfig::new().with_upstream_mode(UpstreamMode::Forward(vec![]));
+        assert_eq!(
+            config.validate(),
+            Err(DnsError::ConfigError(
+                "Forward mode has no upstreams".to_string()
+            ))
+        );
+        let config =
+            ResolverConfig::new().with_forward_zone("corp.internal", vec![addr("10.0.0.1:0")]);
+        assert_eq!(
+            config.validate(),
+            Err(DnsError::ConfigError(
+                "Forward zone corp.internal upstream 10.0.0.1:0 has port 0".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn timeouts_checked() {
+        let config = ResolverConfig::new().with_timeouts(Timeouts {
+            query: Duration::from_secs(2),
+            retries: 1,
+            resolution: Duration::from_secs(1),
+        });
+        assert!(matches!(config.validate(), Err(DnsError::ConfigError(_))));
+    }
+
+    #[test]
+    fn limits_checked() {
+        let config = ResolverConfig::new().with_limits(Limits {
+            referrals: 0,
+            ..Limits::default()
+        });
+        assert_eq!(
+            config.validate(),
+            Err(DnsError::ConfigError(
+                "Limit referrals must be more than zero".to_string()
+            ))
+        );
+        let config = ResolverConfig::new().with_cache_limits(CacheLimits {
+            min_ttl: 600,
+            max_ttl: 60,
+            ..CacheLimits::default()
+        });
+        assert!(matches!(config.validate(), Err(DnsError::ConfigError(_))));
+    }
+}
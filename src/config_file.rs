@@ -0,0 +1,571 @@
+use crate::acl::{Acl, AclAction};
+use crate::cidr::Cidr;
+use crate::config::ResolverConfig;
+use crate::error::DnsError;
+use crate::forward::parse_upstream;
+use crate::local::LocalRecords;
+use crate::rate_limit::{ClientLimits, RateLimits};
+use crate::resolver::{BlockResponse, Blocking, CacheLimits, IpPreference, Timeouts, UpstreamMode};
+use crate::root_hints::RootHints;
+use crate::server::Listener;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Settings for running a server, as read from a TOML config file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerConfig {
+    pub listeners: Vec<Listener>,
+    pub max_in_flight: Option<usize>,
+    pub acl: Acl,
+    pub rate_limits: RateLimits,
+    /// Where to save the cache on shutdown and load it from on start
+    pub cache_snapshot: Option<PathBuf>,
+    pub resolver: ResolverConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    server: ServerSection,
+    listener: Vec<ListenerSection>,
+    acl: AclSection,
+    rate_limit: RateLimitSection,
+    upstream: UpstreamSection,
+    forward_zone: Vec<ForwardZoneSection>,
+    blocking: BlockingSection,
+    cache: CacheSection,
+    logging: LoggingSection,
+    local: LocalSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ServerSection {
+    max_in_flight: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListenerSection {
+    address: String,
+    udp: Option<bool>,
+    tcp: Option<bool>,
+    v6_only: Option<bool>,
+    tcp_idle_timeout_ms: Option<u64>,
+    max_tcp_connections: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AclSection {
+    /// Start from rules allowing loopback and private ranges
+    private_ranges: bool,
+    /// For clients matching no rule
+    default: Option<String>,
+    allow: Vec<String>,
+    deny: Vec<String>,
+    refuse: Vec<String>,
+    allow_local_only: Vec<String>,
+}
+
+impl Default for AclSection {
+    fn default() -> Self {
+        Self {
+            private_ranges: true,
+            default: None,
+            allow: vec![],
+            deny: vec![],
+            refuse: vec![],
+            allow_local_only: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RateLimitSection {
+    queries_per_second: Option<u32>,
+    burst: Option<u32>,
+    responses_per_second: Option<u32>,
+    slip: Option<u32>,
+    /// Limits for particular client ranges, with unset values taken from the section above
+    range: Vec<RateLimitRangeSection>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitRangeSection {
+    cidr: String,
+    queries_per_second: Option<u32>,
+    burst: Option<u32>,
+    responses_per_second: Option<u32>,
+    slip: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct UpstreamSection {
+    /// Resolvers to forward to. Recursive resolution is used when empty.
+    forward: Vec<String>,
+    root_hints: Option<PathBuf>,
+    ip_preference: Option<String>,
+    randomize_case: bool,
+    qname_minimisation: bool,
+    query_timeout_ms: Option<u64>,
+    retries: Option<u32>,
+    resolution_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ForwardZoneSection {
+    suffix: String,
+    upstreams: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BlockingSection {
+    blocklists: Vec<PathBuf>,
+    allowlists: Vec<PathBuf>,
+    response: Option<String>,
+    ttl: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CacheSection {
+    max_entries: Option<usize>,
+    min_ttl: Option<u32>,
+    max_ttl: Option<u32>,
+    snapshot: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LoggingSection {
+    level: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LocalSection {
+    /// Records in zone file layout, e.g. `router.lan 300 IN A 192.168.1.1`
+    records: Vec<String>,
+}
+
+fn invalid(message: impl Into<String>) -> DnsError {
+    DnsError::ConfigError(message.into())
+}
+
+/// Read one domain name per line, skipping blank lines and `#` comments
+pub fn read_name_list(path: &Path) -> io::Result<HashSet<String>> {
+    let mut names = HashSet::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            names.insert(line.to_string());
+        }
+    }
+    Ok(names)
+}
+
+impl ServerConfig {
+    /// Read and validate a config file. Paths in it are relative to the file's directory.
+    pub fn from_file(path: &Path) -> Result<Self, DnsError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| invalid(format!("Failed to read {}: {}", path.display(), err)))?;
+        let base_dir = path.parent().unwrap_or(Path::new("."));
+        Self::from_toml(&contents, base_dir)
+            .map_err(|err| invalid(format!("{}: {}", path.display(), config_message(err))))
+    }
+
+    /// Parse and validate config in TOML, with relative paths resolved from `base_dir`
+    pub fn from_toml(contents: &str, base_dir: &Path) -> Result<Self, DnsError> {
+        let file: FileConfig = toml::from_str(contents).map_err(|err| invalid(err.to_string()))?;
+        let listeners = file
+            .listener
+            .iter()
+            .map(ListenerSection::listener)
+            .collect::<Result<Vec<_>, _>>()?;
+        let acl = file.acl.acl()?;
+        let rate_limits = file.rate_limit.rate_limits()?;
+        let resolver = file.resolver_config(base_dir)?;
+        resolver.validate()?;
+        Ok(Self {
+            listeners,
+            max_in_flight: file.server.max_in_flight,
+            acl,
+            rate_limits,
+            cache_snapshot: file.cache.snapshot.map(|path| base_dir.join(path)),
+            resolver,
+        })
+    }
+}
+
+fn config_message(err: DnsError) -> String {
+    match err {
+        DnsError::ConfigError(message) => message,
+        err => format!("{:?}", err),
+    }
+}
+
+impl ListenerSection {
+    fn listener(&self) -> Result<Listener, DnsError> {
+        let addr = self
+            .address
+            .parse::<SocketAddr>()
+            .map_err(|_| invalid(format!("Invalid listener address: {}", self.address)))?;
+        let defaults = Listener::new(addr);
+        let listener = Listener {
+            udp: self.udp.unwrap_or(defaults.udp),
+            tcp: self.tcp.unwrap_or(defaults.tcp),
+            v6_only: self.v6_only.unwrap_or(defaults.v6_only),
+            tcp_idle_timeout: self
+                .tcp_idle_timeout_ms
+                .map_or(defaults.tcp_idle_timeout, Duration::from_millis),
+            max_tcp_connections: self
+                .max_tcp_connections
+                .unwrap_or(defaults.max_tcp_connections),
+            ..defaults
+        };
+        if !listener.udp && !listener.tcp {
+            return Err(invalid(format!(
+                "Listener {} has neither UDP nor TCP enabled",
+                addr
+            )));
+        }
+        if listener.tcp && listener.tcp_idle_timeout.is_zero() {
+            return Err(invalid(format!(
+                "Listener {} TCP idle timeout must be more than zero",
+                addr
+            )));
+        }
+        Ok(listener)
+    }
+}
+
+impl AclSection {
+    fn acl(&self) -> Result<Acl, DnsError> {
+        let mut acl = match self.private_ranges {
+            true => Acl::default(),
+            false => Acl::new(AclAction::Refuse),
+        };
+        if let Some(default) = &self.default {
+            acl = acl.with_default(default.parse::<AclAction>().map_err(invalid)?);
+        }
+        let rules = [
+            (&self.allow, AclAction::Allow),
+            (&self.deny, AclAction::Deny),
+            (&self.refuse, AclAction::Refuse),
+            (&self.allow_local_only, AclAction::AllowLocalOnly),
+        ];
+        for (blocks, action) in rules {
+            for block in blocks {
+                acl.add(block.parse::<Cidr>().map_err(invalid)?, action);
+            }
+        }
+        Ok(acl)
+    }
+}
+
+fn check_rate_limits(limits: ClientLimits) -> Result<ClientLimits, DnsError> {
+    if limits.queries_per_second == Some(0) || limits.responses_per_second == Some(0) {
+        return Err(invalid("Rate limits must be more than zero"));
+    }
+    Ok(limits)
+}
+
+impl RateLimitSection {
+    fn rate_limits(&self) -> Result<RateLimits, DnsError> {
+        let defaults = ClientLimits::default();
+        let defaults = check_rate_limits(ClientLimits {
+            queries_per_second: self.queries_per_second,
+            burst: self.burst.unwrap_or(defaults.burst),
+            responses_per_second: self.responses_per_second,
+            slip: self.slip.unwrap_or(defaults.slip),
+        })?;
+        let mut rate_limits = RateLimits::new(defaults);
+        for range in &self.range {
+            let limits = check_rate_limits(ClientLimits {
+                queries_per_second: range.queries_per_second.or(defaults.queries_per_second),
+                burst: range.burst.unwrap_or(defaults.burst),
+                responses_per_second: range.responses_per_second.or(defaults.responses_per_second),
+                slip: range.slip.unwrap_or(defaults.slip),
+            })?;
+            rate_limits.add(range.cidr.parse::<Cidr>().map_err(invalid)?, limits);
+        }
+        Ok(rate_limits)
+    }
+}
+
+fn parse_upstreams(upstreams: &[String]) -> Result<Vec<SocketAddr>, DnsError> {
+    upstreams
+        .iter()
+        .map(|upstream| parse_upstream(upstream).map_err(invalid))
+        .collect()
+}
+
+impl FileConfig {
+    fn resolver_config(&self, base_dir: &Path) -> Result<ResolverConfig, DnsError> {
+        let mut config =
+            ResolverConfig::new().with_qname_minimisation(self.upstream.qname_minimisation);
+        if self.upstream.randomize_case {
+            config = config.with_case_randomization(HashSet::new());
+        }
+
+        let upstreams = parse_upstreams(&self.upstream.forward)?;
+        if !upstreams.is_empty() {
+            config = config.with_upstream_mode(UpstreamMode::Forward(upstreams));
+        }
+        if let Some(path) = &self.upstream.root_hints {
+            let root_hints = RootHints::from_file(&base_dir.join(path)).map_err(|err| {
+                invalid(format!(
+                    "Root hints {}: {}",
+                    path.display(),
+                    config_message(err)
+                ))
+            })?;
+            config = config.with_root_hints(root_hints);
+        }
+        if let Some(ip_preference) = &self.upstream.ip_preference {
+            config =
+                config.with_ip_preference(ip_preference.parse::<IpPreference>().map_err(invalid)?);
+        }
+        let defaults = Timeouts::default();
+        config = config.with_timeouts(Timeouts {
+            query: self
+                .upstream
+                .query_timeout_ms
+                .map_or(defaults.query, Duration::from_millis),
+            retries: self.upstream.retries.unwrap_or(defaults.retries),
+            resolution: self
+                .upstream
+                .resolution_timeout_ms
+                .map_or(defaults.resolution, Duration::from_millis),
+        });
+        for zone in &self.forward_zone {
+            config = config.with_forward_zone(&zone.suffix, parse_upstreams(&zone.upstreams)?);
+        }
+
+        let read_names = |paths: &[PathBuf]| -> Result<HashSet<String>, DnsError> {
+            let mut names = HashSet::new();
+            for path in paths {
+                let list = read_name_list(&base_dir.join(path)).map_err(|err| {
+                    invalid(format!("Failed to read {}: {}", path.display(), err))
+                })?;
+                names.extend(list);
+            }
+            Ok(names)
+        };
+        let mut blocking = Blocking::default();
+        if let Some(response) = &self.blocking.response {
+            blocking.response = response.parse::<BlockResponse>().map_err(invalid)?;
+        }
+        if let Some(ttl) = self.blocking.ttl {
+            blocking.ttl = ttl;
+        }
+        config = config
+            .with_blocklist(read_names(&self.blocking.blocklists)?)
+            .with_allowlist(read_names(&self.blocking.allowlists)?)
+            .with_blocking(blocking);
+
+        let defaults = CacheLimits::default();
+        config = config.with_cache_limits(CacheLimits {
+            max_entries: self.cache.max_entries.unwrap_or(defaults.max_entries),
+            min_ttl: self.cache.min_ttl.unwrap_or(defaults.min_ttl),
+            max_ttl: self.cache.max_ttl.unwrap_or(defaults.max_ttl),
+        });
+
+        if let Some(level) = &self.logging.level {
+            let level = level
+                .parse::<LevelFilter>()
+                .map_err(|_| invalid(format!("Invalid log level: {}", level)))?;
+            config = config.with_log_level(level);
+        }
+
+        let mut local_records = LocalRecords::new();
+        for record in &self.local.records {
+            local_records.add(
+                record
+                    .parse()
+                    .map_err(|_| invalid(format!("Invalid local record: {}", record)))?,
+            );
+        }
+        Ok(config.with_local_records(local_records))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn parse(contents: &str) -> Result<ServerConfig, DnsError> {
+        ServerConfig::from_toml(contents, Path::new("."))
+    }
+
+    #[test]
+    fn empty_file_uses_defaults() {
+        let config = parse("").unwrap();
+        assert_eq!(config.listeners, vec![]);
+        assert_eq!(config.acl, Acl::default());
+        assert_eq!(config.rate_limits, RateLimits::default());
+        assert_eq!(config.resolver, ResolverConfig::new());
+    }
+
+    #[test]
+    fn full_file() {
+        let config = parse(
+            r#"
+            [server]
+            max_in_flight = 8
+
+            [[listener]]
+            address = "127.0.0.1:1053"
+
+            [[listener]]
+            address = "[::]:1053"
+            tcp = false
+            v6_only = true
+
+            [acl]
+            allow = ["203.0.113.0/24"]
+            deny = ["10.9.0.0/16"]
+            allow_local_only = ["2001:db8::/32"]
+
+            [rate_limit]
+            queries_per_second = 50
+            responses_per_second = 10
+
+            [[rate_limit.range]]
+            cidr = "192.168.1.50"
+            queries_per_second = 5
+
+            [upstream]
+            forward = ["9.9.9.9", "192.0.2.1:5353"]
+            qname_minimisation = true
+            query_timeout_ms = 500
+
+            [[forward_zone]]
+            suffix = "corp.internal"
+            upstreams = ["10.0.0.2"]
+
+            [blocking]
+            response = "nxdomain"
+            ttl = 60
+
+            [cache]
+            max_entries = 1000
+            snapshot = "cache.txt"
+
+            [logging]
+            level = "debug"
+
+            [local]
+            records = ["router.lan 300 IN A 192.168.1.1"]
+            "#,
+        )
+        .unwrap();
+        let v6_listener = Listener {
+            tcp: false,
+            v6_only: true,
+            ..Listener::new("[::]:1053".parse().unwrap())
+        };
+        assert_eq!(
+            config.listeners,
+            vec![
+                Listener::new("127.0.0.1:1053".parse().unwrap()),
+                v6_listener
+            ]
+        );
+        assert_eq!(config.max_in_flight, Some(8));
+        let client = |ip: &str| config.acl.action_for(ip.parse().unwrap());
+        assert_eq!(client("203.0.113.9"), AclAction::Allow);
+        assert_eq!(client("10.9.1.1"), AclAction::Deny);
+        assert_eq!(client("10.8.1.1"), AclAction::Allow);
+        assert_eq!(client("2001:db8::1"), AclAction::AllowLocalOnly);
+        assert_eq!(client("198.51.100.1"), AclAction::Refuse);
+        let limits = ClientLimits {
+            queries_per_second: Some(50),
+            responses_per_second: Some(10),
+            ..ClientLimits::default()
+        };
+        let iot_limits = ClientLimits {
+            queries_per_second: Some(5),
+            ..limits
+        };
+        assert_eq!(
+            config.rate_limits,
+            RateLimits::new(limits).with_range("192.168.1.50".parse().unwrap(), iot_limits)
+        );
+        let upstreams = vec![
+            "9.9.9.9:53".parse().unwrap(),
+            "192.0.2.1:5353".parse().unwrap(),
+        ];
+        assert_eq!(
+            config.resolver.upstream_mode(),
+            &UpstreamMode::Forward(upstreams)
+        );
+        assert_eq!(config.resolver.cache_limits().max_entries, 1000);
+        assert_eq!(
+            config.cache_snapshot,
+            Some(Path::new(".").join("cache.txt"))
+        );
+        assert_eq!(config.resolver.blocking().response, BlockResponse::NxDomain);
+        assert_eq!(config.resolver.log_level(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn example_file_is_valid() {
+        let config = parse(include_str!("../dnsvisor.example.toml")).unwrap();
+        assert_eq!(
+            config.listeners,
+            vec![Listener::new("127.0.0.1:1053".parse().unwrap())]
+        );
+        assert_eq!(config.acl, Acl::default());
+        assert_eq!(config.rate_limits, RateLimits::default());
+        assert_eq!(config.cache_snapshot, None);
+        assert_eq!(config.resolver, ResolverConfig::new());
+    }
+
+    #[test]
+    fn invalid_files_rejected() {
+        let unknown = parse("[server]\nlisten = \"127.0.0.1:53\"");
+        assert!(matches!(unknown, Err(DnsError::ConfigError(_))));
+        let no_protocols =
+            parse("[[listener]]\naddress = \"127.0.0.1:53\"\nudp = false\ntcp = false");
+        assert_eq!(
+            no_protocols,
+            Err(DnsError::ConfigError(
+                "Listener 127.0.0.1:53 has neither UDP nor TCP enabled".to_string()
+            ))
+        );
+        let bad_record = parse("[local]\nrecords = [\"router.lan A 192.168.1.1\"]");
+        assert_eq!(
+            bad_record,
+            Err(DnsError::ConfigError(
+                "Invalid local record: router.lan A 192.168.1.1".to_string()
+            ))
+        );
+        let zero_rate =
+            parse("[[rate_limit.range]]\ncidr = \"10.0.0.0/8\"\nqueries_per_second = 0");
+        assert_eq!(
+            zero_rate,
+            Err(DnsError::ConfigError(
+                "Rate limits must be more than zero".to_string()
+            ))
+        );
+        let bad_cidr = parse("[acl]\nallow = [\"10.0.0.0/40\"]");
+        assert!(matches!(bad_cidr, Err(DnsError::ConfigError(_))));
+        let invalid_limits = parse("[cache]\nmin_ttl = 600\nmax_ttl = 60");
+        assert!(matches!(invalid_limits, Err(DnsError::ConfigError(_))));
+    }
+}
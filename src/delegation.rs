@@ -0,0 +1,203 @@
+use crate::packet::DnsPacket;
+use crate::record::Rdata;
+use crate::util::{is_subdomain, normalize_name};
+use rand::seq::SliceRandom;
+use std::net::{IpAddr, SocketAddr};
+
+pub const DNS_PORT: u16 = 53;
+
+/// A nameserver and the addresses it can be reached at
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameServer {
+    pub name: String,
+    pub addrs: Vec<SocketAddr>,
+}
+
+/// The nameservers a zone is delegated to, from a referral or the root hints
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delegation {
+    /// Normalized zone name, empty for the root
+    pub zone: String,
+    pub nameservers: Vec<NameServer>,
+    /// Lowest TTL of the NS records, which bounds how long the delegation can be cached
+    pub ttl: u32,
+}
+
+impl Delegation {
+    /// Extract the delegation from a referral sent by a server for `current_zone` while
+    /// resolving `domain_name`. Returns None unless the authority section delegates a zone which
+    /// is below `current_zone` and contains `domain_name`, so a server can't redirect resolution
+    /// to a zone it isn't responsible for. Glue is only kept for the delegated nameservers, and
+    /// only when it is inside `current_zone`.
+    pub fn from_referral(
+        response: &DnsPacket,
+        current_zone: &str,
+        domain_name: &str,
+    ) -> Option<Delegation> {
+        let domain_name = normalize_name(domain_name);
+        let zone = response
+            .authorities
+            .iter()
+            .filter(|record| matches!(record.rdata, Rdata::NS(_)))
+            .map(|record| normalize_name(&record.name))
+            .find(|zone| {
+                zone != current_zone
+                    && is_subdomain(zone, current_zone)
+                    && is_subdomain(&domain_name, zone)
+            })?;
+        let mut nameservers: Vec<NameServer> = vec![];
+        let mut ttl = u32::MAX;
+        for record in &response.authorities {
+            if let Rdata::NS(ns_name) = &record.rdata {
+                if normalize_name(&record.name) != zone {
+                    continue;
+                }
+                ttl = ttl.min(record.ttl);
+                let ns_name = normalize_name(ns_name);
+                if !nameservers.iter().any(|ns| ns.name == ns_name) {
+                    nameservers.push(NameServer {
+                        name: ns_name,
+                        addrs: vec![],
+                    });
+                }
+            }
+        }
+        for record in &response.additionals {
+            let ip: Option<IpAddr> = match &record.rdata {
+                Rdata::A(string) | Rdata::AAAA(string) => string.parse().ok(),
+                _ => None,
+            };
+            let name = normalize_name(&record.name);
+            if !is_subdomain(&name, current_zone) {
+                continue;
+            }
+            if let (Some(ip), Some(ns)) = (ip, nameservers.iter_mut().find(|ns| ns.name == name)) {
+                ns.addrs.push(SocketAddr::new(ip, DNS_PORT));
+            }
+        }
+        Some(Delegation {
+            zone,
+            nameservers,
+            ttl,
+        })
+    }
+
+    /// Addresses of all nameservers in random order
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs: Vec<SocketAddr> = self
+            .nameservers
+            .iter()
+            .flat_map(|ns| ns.addrs.iter().copied())
+            .collect();
+        addrs.shuffle(&mut rand::thread_rng());
+        addrs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::DnsHeader;
+    use crate::record::DnsRecord;
+    use crate::rr_fields::Class;
+    use pretty_assertions::assert_eq;
+
+    fn record(name: &str, rdata: Rdata) -> DnsRecord {
+        DnsRecord {
+            name: name.to_string(),
+            class: Class::CLASS_IN,
+            ttl: 172800,
+            rdata,
+        }
+    }
+
+    fn referral(authorities: Vec<DnsRecord>, additionals: Vec<DnsRecord>) -> DnsPacket {
+        DnsPacket {
+            header: DnsHeader::simple_query_header(),
+            questions: vec![],
+            answers: vec![],
+            authorities,
+            additionals,
+        }
+    }
+
+    #[test]
+    fn referral_glue_matched_to_nameservers() {
+        let response = referral(
+            vec![
+                record("example.com", Rdata::NS("ns1.example.com".to_string())),
+                record("example.com", Rdata::NS("ns.other.net".to_string())),
+            ],
+            vec![
+                record("ns1.example.com", Rdata::A("192.0.2.1".to_string())),
+                record("unrelated.example.com", Rdata::A("192.0.2.2".to_string())),
+            ],
+        );
+        let res = Delegation::from_referral(&response, "com", "www.example.com");
+        let expected = Some(Delegation {
+            zone: "example.com".to_string(),
+            nameservers: vec![
+                NameServer {
+                    name: "ns1.example.com".to_string(),
+                    addrs: vec!["192.0.2.1:53".parse().unwrap()],
+                },
+                NameServer {
+                    name: "ns.other.net".to_string(),
+                    addrs: vec![],
+                },
+            ],
+            ttl: 172800,
+        });
+        assert_eq!(res, expected);
+        let addrs: Vec<SocketAddr> = vec!["192.0.2.1:53".parse().unwrap()];
+        assert_eq!(res.unwrap().addrs(), addrs);
+    }
+    #[test]
+    fn referral_out_of_bailiwick_glue_discarded() {
+        let response = referral(
+            vec![record(
+                "example.com",
+                Rdata::NS("ns.example.net".to_string()),
+            )],
+            vec![record("ns.example.net", Rdata::A("192.0.2.66".to_string()))],
+        );
+        let delegation = Delegation::from_referral(&response, "com", "www.example.com").unwrap();
+        assert!(delegation.addrs().is_empty());
+    }
+    #[test]
+    fn referral_outside_current_zone_rejected() {
+        let response = referral(
+            vec![record(
+                "example.net",
+                Rdata::NS("ns.example.net".to_string()),
+            )],
+            vec![],
+        );
+        assert_eq!(
+            Delegation::from_referral(&response, "com", "www.example.net"),
+            None
+        );
+    }
+    #[test]
+    fn referral_not_containing_name_rejected() {
+        let response = referral(
+            vec![record("other.com", Rdata::NS("ns.other.com".to_string()))],
+            vec![],
+        );
+        assert_eq!(
+            Delegation::from_referral(&response, "com", "www.example.com"),
+            None
+        );
+    }
+    #[test]
+    fn referral_to_same_zone_rejected() {
+        let response = referral(
+            vec![record("com", Rdata::NS("a.gtld-servers.net".to_string()))],
+            vec![],
+        );
+        assert_eq!(
+            Delegation::from_referral(&response, "com", "www.example.com"),
+            None
+        );
+    }
+}
@@ -1,9 +1,13 @@
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DnsError {
     ResolveError(String),
     EncodeError(&'static str),
     DecodeError(String),
     NetworkError(&'static str),
+    TimeoutError(&'static str),
+    DeadlineError(String),
+    LimitError(String),
     CacheError(&'static str),
     NotImplementedError(String),
+    ConfigError(String),
 }
@@ -0,0 +1,92 @@
+use crate::delegation::DNS_PORT;
+use crate::util::{is_subdomain, normalize_name};
+use std::net::{IpAddr, SocketAddr};
+
+/// Parse an upstream resolver given as `IP` or `IP:PORT`, defaulting to port 53
+pub fn parse_upstream(value: &str) -> Result<SocketAddr, String> {
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, DNS_PORT));
+    }
+    value
+        .parse::<SocketAddr>()
+        .map_err(|_| format!("invalid upstream address: {value}"))
+}
+
+/// Upstream resolvers responsible for every name at or below `suffix`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardZone {
+    pub suffix: String,
+    pub upstreams: Vec<SocketAddr>,
+}
+
+/// Per-suffix forwarding rules, like dnsmasq's `server=/corp.internal/10.0.0.2`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ForwardZones {
+    zones: Vec<ForwardZone>,
+}
+
+impl ForwardZones {
+    pub fn new() -> Self {
+        Self { zones: vec![] }
+    }
+
+    /// Add a rule, replacing any existing rule for the same suffix
+    pub fn add(&mut self, suffix: &str, upstreams: Vec<SocketAddr>) {
+        let suffix = normalize_name(suffix);
+        self.zones.retain(|zone| zone.suffix != suffix);
+        self.zones.push(ForwardZone { suffix, upstreams });
+    }
+
+    /// Find the rule with the longest suffix containing `name`
+    pub fn lookup(&self, name: &str) -> Option<&ForwardZone> {
+        let name = normalize_name(name);
+        self.zones
+            .iter()
+            .filter(|zone| is_subdomain(&name, &zone.suffix))
+            .max_by_key(|zone| zone.suffix.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn addr(ip: &str) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), 53)
+    }
+
+    #[test]
+    fn lookup_longest_suffix() {
+        let mut zones = ForwardZones::new();
+        zones.add("internal", vec![addr("10.0.0.1")]);
+        zones.add("corp.internal", vec![addr("10.0.0.2")]);
+        let res = zones.lookup("host.corp.internal").map(|zone| &zone.suffix);
+        assert_eq!(res, Some(&"corp.internal".to_string()));
+        let res = zones.lookup("other.internal").map(|zone| &zone.suffix);
+        assert_eq!(res, Some(&"internal".to_string()));
+    }
+    #[test]
+    fn lookup_label_boundary() {
+        let mut zones = ForwardZones::new();
+        zones.add("corp.internal", vec![addr("10.0.0.2")]);
+        assert_eq!(zones.lookup("evilcorp.internal"), None);
+        assert!(zones.lookup("corp.internal").is_some());
+    }
+    #[test]
+    fn lookup_case_and_trailing_dot() {
+        let mut zones = ForwardZones::new();
+        zones.add("10.in-addr.arpa.", vec![addr("10.0.0.2")]);
+        assert!(zones.lookup("4.3.2.10.IN-ADDR.ARPA").is_some());
+    }
+    #[test]
+    fn add_replaces_suffix() {
+        let mut zones = ForwardZones::new();
+        zones.add("corp.internal", vec![addr("10.0.0.2")]);
+        zones.add("corp.internal", vec![addr("10.0.0.3")]);
+        let res = zones
+            .lookup("corp.internal")
+            .map(|zone| zone.upstreams.clone());
+        assert_eq!(res, Some(vec![addr("10.0.0.3")]));
+    }
+}
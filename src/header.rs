@@ -1,6 +1,7 @@
-use crate::cursor_read_num;
 use crate::error::DnsError;
-use rand::random;
+use crate::rr_fields::HeaderFlags;
+use rand::rngs::OsRng;
+use rand::Rng;
 use std::io::Cursor;
 use std::io::Read;
 #[derive(Debug, PartialEq, Clone)]
@@ -38,7 +39,8 @@ impl DnsHeader {
     }
 
     pub fn simple_query_header() -> Self {
-        let id: u16 = random();
+        // Query IDs must be unpredictable so off-path attackers can't forge responses
+        let id: u16 = OsRng.gen();
         let no_recursion = 0;
         Self {
             id,
@@ -49,12 +51,21 @@ impl DnsHeader {
             num_additionals: 0,
         }
     }
+
+    pub fn recursive_query_header() -> Self {
+        let mut header = Self::simple_query_header();
+        header.flags |= HeaderFlags::RD_RECURSION_DESIRED as u16;
+        header
+    }
+
+    pub fn rcode(&self) -> u16 {
+        self.flags & HeaderFlags::RCODE_MASK as u16
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use hex;
     use pretty_assertions::assert_eq;
     use std::io::Cursor;
     #[test]
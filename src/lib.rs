@@ -1,12 +1,30 @@
 #![warn(clippy::unwrap_used, clippy::panic, clippy::print_stdout)]
 
+pub mod acl;
+#[cfg(feature = "tokio")]
+pub mod async_resolver;
 mod cache;
+pub mod cidr;
+pub mod clock;
+mod coalesce;
+pub mod config;
+pub mod config_file;
+pub mod delegation;
 pub mod error;
+pub mod forward;
 #[macro_use]
 mod util;
-mod header;
+pub mod header;
+pub mod local;
+mod minimise;
 pub mod packet;
-mod question;
-mod record;
+pub mod question;
+pub mod rate_limit;
+pub mod record;
 pub mod resolver;
+pub mod root_hints;
 pub mod rr_fields;
+mod rtt;
+pub mod server;
+pub mod trace;
+pub mod transport;
@@ -0,0 +1,64 @@
+use crate::question::DnsQuestion;
+use crate::record::DnsRecord;
+use crate::rr_fields::Type;
+use crate::util::normalize_name;
+use std::collections::HashMap;
+
+/// Records answered directly instead of being resolved, like entries in `/etc/hosts`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LocalRecords {
+    // Keyed by normalized owner name
+    records: HashMap<String, Vec<DnsRecord>>,
+}
+
+impl LocalRecords {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, record: DnsRecord) {
+        let name = normalize_name(&record.name);
+        self.records.entry(name).or_default().push(record);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Records answering `question`, or None when the name has no local records and should be
+    /// resolved as usual. A name with local records of other types gets an empty answer, and a
+    /// local CNAME answers every type.
+    pub fn lookup(&self, question: &DnsQuestion) -> Option<Vec<DnsRecord>> {
+        let records = self.records.get(&normalize_name(&question.name))?;
+        Some(
+            records
+                .iter()
+                .filter(|record| {
+                    let rtype = record.get_type();
+                    rtype == question.qtype || rtype == Type::CNAME
+                })
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rr_fields::Class;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn lookup_by_name_and_type() {
+        let mut local = LocalRecords::new();
+        let record: DnsRecord = "router.lan 300 IN A 192.168.1.1".parse().unwrap();
+        local.add(record.clone());
+        let question = DnsQuestion::new("Router.lan.", Type::A, Class::CLASS_IN);
+        assert_eq!(local.lookup(&question), Some(vec![record]));
+        let question = DnsQuestion::new("router.lan", Type::AAAA, Class::CLASS_IN);
+        assert_eq!(local.lookup(&question), Some(vec![]));
+        let question = DnsQuestion::new("nas.lan", Type::A, Class::CLASS_IN);
+        assert_eq!(local.lookup(&question), None);
+    }
+}
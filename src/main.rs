@@ -1,18 +1,28 @@
 #![warn(clippy::unwrap_used, clippy::panic)]
-use clap::{Arg, Command};
-use dnsvisor::packet::DnsPacket;
-use dnsvisor::resolver::Resolver;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use dnsvisor::acl::{Acl, AclAction};
+use dnsvisor::cidr::Cidr;
+use dnsvisor::config::ResolverConfig;
+use dnsvisor::config_file::{read_name_list, ServerConfig};
+use dnsvisor::error::DnsError;
+use dnsvisor::forward::{parse_upstream, ForwardZone};
+use dnsvisor::rate_limit::RateLimits;
+use dnsvisor::resolver::{BlockResponse, CacheLimits, IpPreference, Resolver, UpstreamMode};
+use dnsvisor::root_hints::RootHints;
 use dnsvisor::rr_fields::Type;
-use log::{debug, error, warn};
+use dnsvisor::server::{Listener, Listeners, Server, ServerHandle};
+use log::{error, info, warn, LevelFilter};
 use std::collections::HashSet;
-use std::fs::File;
-use std::io::{self, stdin, stdout, BufRead, BufReader, Write};
-use std::net::{IpAddr, SocketAddr, UdpSocket};
-use std::path::PathBuf;
+use std::io::{stdin, stdout, ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::Arc;
+use std::thread;
 
 fn interactive() {
-    let mut resolver = Resolver::default();
+    let resolver = Resolver::default();
+    prime(&resolver);
     loop {
         print!("Enter a domain> ");
         stdout().flush().unwrap_or_else(|_| {
@@ -40,80 +50,317 @@ fn interactive() {
     }
 }
 
-fn server(ip: &IpAddr, port: &u16, blocklist_option: Option<&PathBuf>) {
-    let blocklist = build_blocklist(blocklist_option).unwrap_or_else(|_| {
-        eprintln!("Failed to read blocklist");
-        exit(1);
-    });
-    let mut resolver = Resolver::new(blocklist);
-    let addr = SocketAddr::from((*ip, *port));
-    let socket = UdpSocket::bind(addr).unwrap_or_else(|_| {
-        eprintln!("Failed to bind to socket");
-        exit(1);
-    });
-    debug!("Server listening on {:?}", socket);
-    loop {
-        // Per RFC 1035 the max size for UDP messages is 512 bytes
-        let mut buf = [0u8; 512];
-        let (_n_bytes, src_addr) = match socket.recv_from(&mut buf) {
-            Ok((n_bytes, src_addr)) => (n_bytes, src_addr),
-            Err(_) => {
-                error!("Failed to receive request from socket");
-                continue;
-            }
-        };
-        debug!("Received request from {:?}", src_addr);
-        match DnsPacket::from_bytes(&buf[..]) {
-            Ok(query_packet) => match resolver.resolve_packet(query_packet.clone()) {
-                Ok(response_packet) => send_response(response_packet, &src_addr, &socket),
-                Err(err) => {
-                    error!(
-                        "Resolver failed with error {:?}. Sending error response.",
-                        err
-                    );
-                    let err_packet = query_packet.make_error_response(err);
-                    send_response(err_packet, &src_addr, &socket)
-                }
-            },
-            Err(err) => error!(
-                "Failed to decode request packet with error {:?}. Skipping.",
+fn exit_config_error(err: DnsError) -> ! {
+    match err {
+        DnsError::ConfigError(message) => eprintln!("Invalid configuration: {}", message),
+        err => eprintln!("Invalid configuration: {:?}", err),
+    }
+    exit(1);
+}
+
+fn load_config(path: &Path) -> ServerConfig {
+    ServerConfig::from_file(path).unwrap_or_else(|err| exit_config_error(err))
+}
+
+/// What the server runs with, from the config file with flags applied on top
+#[derive(Clone)]
+struct ServerSettings {
+    listeners: Vec<Listener>,
+    max_in_flight: Option<usize>,
+    acl: Acl,
+    rate_limits: RateLimits,
+    cache_snapshot: Option<PathBuf>,
+    resolver: ResolverConfig,
+}
+
+impl ServerSettings {
+    /// Whether changing to `other` needs a restart rather than a reload
+    fn needs_restart(&self, other: &ServerSettings) -> bool {
+        self.listeners != other.listeners
+            || self.max_in_flight != other.max_in_flight
+            || self.cache_snapshot != other.cache_snapshot
+            || self.resolver.log_level() != other.resolver.log_level()
+    }
+}
+
+/// Read the config file, if any, and apply server flags on top. Also used to reload, so the
+/// config file and lists it names are read again each time.
+fn server_settings(matches: &ArgMatches) -> Result<ServerSettings, DnsError> {
+    let file_config = match matches.get_one::<PathBuf>("config") {
+        Some(path) => ServerConfig::from_file(path)?,
+        None => ServerConfig::default(),
+    };
+    let mut listen: Vec<SocketAddr> = matches
+        .get_many::<SocketAddr>("listen")
+        .unwrap_or_default()
+        .copied()
+        .collect();
+    let ip_address = matches.get_one::<IpAddr>("ip_address");
+    let port = matches.get_one::<u16>("port");
+    if let (Some(ip_address), Some(port)) = (ip_address, port) {
+        listen.push(SocketAddr::new(*ip_address, *port));
+    }
+    let listeners = match listen.is_empty() {
+        true => file_config.listeners,
+        false => listen.into_iter().map(Listener::new).collect(),
+    };
+    let max_in_flight = matches
+        .get_one::<usize>("max_in_flight")
+        .copied()
+        .or(file_config.max_in_flight);
+    let mut acl = file_config.acl;
+    for (cidr, action) in matches
+        .get_many::<(Cidr, AclAction)>("acl")
+        .unwrap_or_default()
+    {
+        acl.add(*cidr, *action);
+    }
+    let mut rate_limits = file_config.rate_limits;
+    let mut default_limits = *rate_limits.default_limits();
+    if let Some(queries_per_second) = matches.get_one::<u32>("rate_limit") {
+        default_limits.queries_per_second = Some(*queries_per_second);
+    }
+    if let Some(responses_per_second) = matches.get_one::<u32>("response_rate_limit") {
+        default_limits.responses_per_second = Some(*responses_per_second);
+    }
+    rate_limits = rate_limits.with_default(default_limits);
+    let cache_snapshot = matches
+        .get_one::<PathBuf>("cache_snapshot")
+        .cloned()
+        .or(file_config.cache_snapshot);
+    Ok(ServerSettings {
+        listeners,
+        max_in_flight,
+        acl,
+        rate_limits,
+        cache_snapshot,
+        resolver: build_config(matches, file_config.resolver)?,
+    })
+}
+
+/// Apply server flags on top of `config`, so flags override the config file
+fn build_config(
+    matches: &ArgMatches,
+    mut config: ResolverConfig,
+) -> Result<ResolverConfig, DnsError> {
+    if let Some(blocklist_path) = matches.get_one::<PathBuf>("blocklist") {
+        let blocklist = read_name_list(blocklist_path).map_err(|err| {
+            DnsError::ConfigError(format!(
+                "Failed to read blocklist {}: {}",
+                blocklist_path.display(),
                 err
-            ),
+            ))
+        })?;
+        config = config.with_blocklist(blocklist);
+    }
+    let upstreams: Vec<SocketAddr> = matches
+        .get_many::<SocketAddr>("forward")
+        .map(|values| values.copied().collect())
+        .unwrap_or_default();
+    if !upstreams.is_empty() {
+        config = config.with_upstream_mode(UpstreamMode::Forward(upstreams));
+    }
+    let mut blocking = config.blocking().clone();
+    if let Some(response) = matches.get_one::<BlockResponse>("block_response") {
+        blocking.response = *response;
+    }
+    if let Some(ttl) = matches.get_one::<u32>("blocked_ttl") {
+        blocking.ttl = *ttl;
+    }
+    config = config.with_blocking(blocking);
+    if matches.get_flag("qname_minimisation") {
+        config = config.with_qname_minimisation(true);
+    }
+    if let Some(ip_preference) = matches.get_one::<IpPreference>("ip_preference") {
+        config = config.with_ip_preference(*ip_preference);
+    }
+    if let Some(forward_zones) = matches.get_many::<ForwardZone>("forward_zone") {
+        for zone in forward_zones {
+            config = config.with_forward_zone(&zone.suffix, zone.upstreams.clone());
         }
     }
+    if let Some(root_hints_path) = matches.get_one::<PathBuf>("root_hints") {
+        config = config.with_root_hints(RootHints::from_file(root_hints_path)?);
+    }
+    if matches.get_flag("randomize_case") {
+        config = config.with_case_randomization(HashSet::new());
+    }
+    if let Some(max_entries) = matches.get_one::<usize>("cache_size") {
+        let cache_limits = CacheLimits {
+            max_entries: *max_entries,
+            ..config.cache_limits().clone()
+        };
+        config = config.with_cache_limits(cache_limits);
+    }
+    if let Some(log_level) = matches.get_one::<LevelFilter>("log_level") {
+        config = config.with_log_level(*log_level);
+    }
+    Ok(config)
 }
 
-fn build_blocklist(blocklist_option: Option<&PathBuf>) -> io::Result<HashSet<String>> {
-    let mut blocklist = HashSet::new();
-    if let Some(blocklist_path) = blocklist_option {
-        let file = File::open(blocklist_path)?;
-        let reader = BufReader::new(file);
-        for line_result in reader.lines() {
-            let line = line_result?;
-            // skip commented lines
-            if !line.starts_with('#') {
-                blocklist.insert(line);
-            }
-        }
+fn build_resolver(config: ResolverConfig) -> Result<Resolver, DnsError> {
+    let upstream_mode_is_recursive = *config.upstream_mode() == UpstreamMode::Recursive;
+    let resolver = config.build()?;
+    if upstream_mode_is_recursive {
+        prime(&resolver);
+    }
+    Ok(resolver)
+}
+
+/// Warm the cache from a snapshot saved on the last shutdown, if there is one
+fn load_cache_snapshot(resolver: &Resolver, path: &Path) {
+    match resolver.load_cache(path) {
+        Ok(count) => info!("Loaded {} cached records from {}", count, path.display()),
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => warn!("Failed to load cache snapshot {}: {}", path.display(), err),
+    }
+}
+
+fn save_cache_snapshot(resolver: &Resolver, path: &Path) {
+    match resolver.save_cache(path) {
+        Ok(count) => info!("Saved {} cached records to {}", count, path.display()),
+        Err(err) => warn!("Failed to save cache snapshot {}: {}", path.display(), err),
     }
-    Ok(blocklist)
 }
 
-fn send_response(packet: DnsPacket, src_addr: &SocketAddr, socket: &UdpSocket) {
-    debug!("Sending response to {:?}", src_addr);
-    match packet.to_bytes() {
-        Ok(bytes) => {
-            if let Err(err) = socket.send_to(&bytes, src_addr) {
-                error!("Failed to send response with error: {:?}. Skipping.", err)
+/// Swap in a resolver, ACL and rate limits built from the config file and flags read afresh,
+/// keeping the cache and the listening sockets. Returns the settings read, so the next reload
+/// only warns about changes made since this one.
+fn reload(
+    matches: &ArgMatches,
+    running: &ServerSettings,
+    handle: &ServerHandle,
+) -> Result<ServerSettings, DnsError> {
+    let settings = server_settings(matches)?;
+    if running.needs_restart(&settings) {
+        warn!("Changes to listeners, max_in_flight, the cache snapshot and logging need a restart");
+    }
+    // Not primed, as the cache comes from the running resolver and priming could keep the
+    // signal handler busy until it times out
+    let resolver = settings.resolver.clone().build()?;
+    resolver.copy_cache_from(&handle.resolver());
+    handle.reload(
+        Arc::new(resolver),
+        settings.acl.clone(),
+        settings.rate_limits.clone(),
+    );
+    info!("Reloaded configuration");
+    Ok(settings)
+}
+
+/// Shut down on SIGTERM or SIGINT, and reload on SIGHUP. A second SIGTERM or SIGINT exits
+/// without waiting for queries in flight.
+#[cfg(unix)]
+fn handle_signals(matches: ArgMatches, mut running: ServerSettings, handle: ServerHandle) {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = match Signals::new([SIGHUP, SIGINT, SIGTERM]) {
+        Ok(signals) => signals,
+        Err(err) => {
+            warn!("Failed to handle signals: {}", err);
+            return;
+        }
+    };
+    thread::spawn(move || {
+        let mut stopping = false;
+        for signal in signals.forever() {
+            match signal {
+                SIGHUP => {
+                    info!("Reloading configuration");
+                    match reload(&matches, &running, &handle) {
+                        Ok(settings) => running = settings,
+                        Err(err) => error!(
+                            "Failed to reload, keeping the running configuration: {:?}",
+                            err
+                        ),
+                    }
+                }
+                _ if stopping => {
+                    warn!("Exiting without waiting for queries in flight");
+                    exit(1);
+                }
+                _ => {
+                    info!("Shutting down");
+                    stopping = true;
+                    handle.shutdown();
+                }
             }
         }
-        Err(err) => error!(
-            "Failed to encode the response with error: {:?}. Skipping.",
+    });
+}
+
+#[cfg(not(unix))]
+fn handle_signals(_matches: ArgMatches, _running: ServerSettings, _handle: ServerHandle) {}
+
+/// Log at `level`, unless RUST_LOG says otherwise
+fn init_logging(level: LevelFilter) {
+    env_logger::builder()
+        .format_timestamp(None)
+        .filter_level(level)
+        .parse_default_env()
+        .init();
+}
+
+fn serve(listeners: &[Listener], server: &Server) {
+    let listeners = Listeners::bind(listeners).unwrap_or_else(|err| {
+        eprintln!("Failed to bind to socket: {}", err);
+        exit(1);
+    });
+    if let Err(err) = server.serve(listeners) {
+        eprintln!("Server failed with error: {:?}", err);
+        exit(1);
+    }
+}
+
+fn trace(domain_name: &str, record_type: Type) {
+    let resolver = Resolver::default();
+    prime(&resolver);
+    let trace = resolver.trace(domain_name, record_type);
+    println!("{}", trace);
+    if trace.result.is_err() {
+        exit(1);
+    }
+}
+
+fn prime(resolver: &Resolver) {
+    if let Err(err) = resolver.prime() {
+        warn!(
+            "Root priming failed with error {:?}. Using root hints.",
             err
-        ),
+        );
     }
 }
 
+fn parse_type(value: &str) -> Result<Type, String> {
+    value
+        .parse::<Type>()
+        .map_err(|_| format!("unsupported record type: {value}"))
+}
+
+/// Parse a forwarding rule given as `SUFFIX=IP[:PORT][,IP[:PORT]...]`
+fn parse_forward_zone(value: &str) -> Result<ForwardZone, String> {
+    let (suffix, upstreams) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected SUFFIX=IP[:PORT][,...], got: {value}"))?;
+    let upstreams = upstreams
+        .split(',')
+        .map(parse_upstream)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(ForwardZone {
+        suffix: suffix.to_string(),
+        upstreams,
+    })
+}
+
+/// Parse an access rule given as `CIDR=ACTION`
+fn parse_acl_rule(value: &str) -> Result<(Cidr, AclAction), String> {
+    let (cidr, action) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected CIDR=ACTION, got: {value}"))?;
+    Ok((cidr.parse()?, action.parse()?))
+}
+
 macro_rules! exit_invalid_args {
     () => {{
         eprintln!("Error: invalid arguments passed");
@@ -122,26 +369,71 @@ macro_rules! exit_invalid_args {
 }
 
 fn main() {
-    env_logger::builder().format_timestamp(None).init();
     let cmd = Command::new("dnsvisor")
         .about("DNS resolver")
         .subcommand_required(true)
         .subcommand(Command::new("interactive").about("Interactive prompt to look up DNS records"))
+        .subcommand(
+            Command::new("trace")
+                .about("Resolve a name from the root and print each step, like dig +trace")
+                .arg(
+                    Arg::new("domain")
+                        .help("Domain name to resolve")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("type")
+                        .help("Record type to resolve")
+                        .default_value("A")
+                        .value_parser(parse_type),
+                ),
+        )
+        .subcommand(
+            Command::new("check-config")
+                .about("Validate a config file without starting the server")
+                .arg(
+                    Arg::new("config")
+                        .help("TOML config file")
+                        .value_name("FILE")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                ),
+        )
         .subcommand(
             Command::new("server")
                 .about("UDP server to respond to DNS Requests")
                 .arg(
                     Arg::new("ip_address")
-                        .help("Server IP Address")
-                        .required(true)
+                        .help("Server IP Address. Overrides listeners in the config file")
+                        .required(false)
+                        .requires("port")
                         .value_parser(clap::value_parser!(IpAddr)),
                 )
                 .arg(
                     Arg::new("port")
                         .help("Server Port")
-                        .required(true)
+                        .required(false)
                         .value_parser(clap::value_parser!(u16)),
                 )
+                .arg(
+                    Arg::new("listen")
+                        .short('l')
+                        .long("listen")
+                        .help("Address to answer on over UDP and TCP, e.g. [::]:53. Repeat for more addresses. Overrides listeners in the config file")
+                        .value_name("IP:PORT")
+                        .required(false)
+                        .action(ArgAction::Append)
+                        .value_parser(clap::value_parser!(SocketAddr)),
+                )
+                .arg(
+                    Arg::new("config")
+                        .short('c')
+                        .long("config")
+                        .help("TOML config file. Flags override its values")
+                        .value_name("FILE")
+                        .required(false)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
                 .arg(
                     Arg::new("blocklist")
                         .short('b')
@@ -150,20 +442,181 @@ fn main() {
                         .value_name("FILE")
                         .required(false)
                         .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("forward")
+                        .short('f')
+                        .long("forward")
+                        .help("Forward queries to this upstream resolver instead of recursing. Repeat for failover")
+                        .value_name("IP[:PORT]")
+                        .required(false)
+                        .action(ArgAction::Append)
+                        .value_parser(parse_upstream),
+                )
+                .arg(
+                    Arg::new("forward_zone")
+                        .short('z')
+                        .long("forward-zone")
+                        .help("Forward queries for a domain suffix to its own upstreams, e.g. corp.internal=10.0.0.2. Repeatable")
+                        .value_name("SUFFIX=IP[:PORT][,...]")
+                        .required(false)
+                        .action(ArgAction::Append)
+                        .value_parser(parse_forward_zone),
+                )
+                .arg(
+                    Arg::new("root_hints")
+                        .short('r')
+                        .long("root-hints")
+                        .help("Root hints file in named.root format. Defaults to the built-in root servers")
+                        .value_name("FILE")
+                        .required(false)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("randomize_case")
+                        .long("randomize-case")
+                        .help("Randomize the case of query names sent upstream (DNS 0x20) to make spoofing harder")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("qname_minimisation")
+                        .long("qname-minimisation")
+                        .help("Only reveal as much of each query name to nameservers as needed (RFC 9156)")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("ip_preference")
+                        .long("ip-preference")
+                        .help("Address families used to reach nameservers: ipv4-first, ipv6-first, ipv4-only or ipv6-only")
+                        .value_name("PREFERENCE")
+                        .required(false)
+                        .value_parser(clap::value_parser!(IpPreference)),
+                )
+                .arg(
+                    Arg::new("acl")
+                        .long("acl")
+                        .help("Client access rule as CIDR=ACTION, where ACTION is allow, deny, refuse or allow-local-only. Repeat for more rules. Loopback and private ranges are allowed and other clients refused unless a rule says otherwise")
+                        .value_name("CIDR=ACTION")
+                        .required(false)
+                        .action(ArgAction::Append)
+                        .value_parser(parse_acl_rule),
+                )
+                .arg(
+                    Arg::new("rate_limit")
+                        .long("rate-limit")
+                        .help("Queries each client may send per second. Overrides rate_limit.queries_per_second in the config file")
+                        .value_name("QPS")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u32).range(1..)),
+                )
+                .arg(
+                    Arg::new("response_rate_limit")
+                        .long("response-rate-limit")
+                        .help("Identical responses sent per second to each client network over UDP, beyond which they're dropped or truncated. Overrides rate_limit.responses_per_second in the config file")
+                        .value_name("RPS")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u32).range(1..)),
+                )
+                .arg(
+                    Arg::new("max_in_flight")
+                        .long("max-in-flight")
+                        .help("Maximum number of queries resolved at once (default 32)")
+                        .value_name("COUNT")
+                        .required(false)
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("block_response")
+                        .long("block-response")
+                        .help("How blocklisted names are answered: null-address (0.0.0.0 or ::) or nxdomain")
+                        .value_name("RESPONSE")
+                        .required(false)
+                        .value_parser(clap::value_parser!(BlockResponse)),
+                )
+                .arg(
+                    Arg::new("blocked_ttl")
+                        .long("blocked-ttl")
+                        .help("TTL in seconds of answers for blocklisted names (default 43200)")
+                        .value_name("SECONDS")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u32)),
+                )
+                .arg(
+                    Arg::new("cache_size")
+                        .long("cache-size")
+                        .help("Maximum number of records cached (default 100000)")
+                        .value_name("ENTRIES")
+                        .required(false)
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("cache_snapshot")
+                        .long("cache-snapshot")
+                        .help("Save the cache to this file on shutdown and load it on start, so a restart doesn't start cold")
+                        .value_name("FILE")
+                        .required(false)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("log_level")
+                        .long("log-level")
+                        .help("Log level: off, error, warn, info, debug or trace. RUST_LOG takes precedence")
+                        .value_name("LEVEL")
+                        .required(false)
+                        .value_parser(clap::value_parser!(LevelFilter)),
                 ),
         );
     let matches = cmd.get_matches();
     match matches.subcommand() {
-        Some(("interactive", _matches)) => interactive(),
-        Some(("server", matches)) => {
-            let ip_address = matches
-                .get_one::<IpAddr>("ip_address")
+        Some(("interactive", _matches)) => {
+            init_logging(LevelFilter::Error);
+            interactive()
+        }
+        Some(("trace", matches)) => {
+            init_logging(LevelFilter::Error);
+            let domain_name = matches
+                .get_one::<String>("domain")
                 .unwrap_or_else(|| exit_invalid_args!());
-            let port = matches
-                .get_one::<u16>("port")
+            let record_type = matches
+                .get_one::<Type>("type")
+                .unwrap_or_else(|| exit_invalid_args!());
+            trace(domain_name, *record_type);
+        }
+        Some(("server", matches)) => {
+            let settings = server_settings(matches).unwrap_or_else(|err| exit_config_error(err));
+            if settings.listeners.is_empty() {
+                eprintln!(
+                    "Error: pass IP_ADDRESS and PORT, --listen, or add a [[listener]] to --config"
+                );
+                exit(1);
+            }
+            init_logging(settings.resolver.log_level());
+            let resolver = build_resolver(settings.resolver.clone())
+                .unwrap_or_else(|err| exit_config_error(err));
+            if let Some(path) = &settings.cache_snapshot {
+                load_cache_snapshot(&resolver, path);
+            }
+            let mut server = Server::new(Arc::new(resolver))
+                .with_acl(settings.acl.clone())
+                .with_rate_limits(settings.rate_limits.clone());
+            if let Some(max_in_flight) = settings.max_in_flight {
+                server = server.with_max_in_flight(max_in_flight);
+            }
+            let handle = server.control();
+            handle_signals(matches.clone(), settings.clone(), handle.clone());
+            serve(&settings.listeners, &server);
+            if let Some(path) = &settings.cache_snapshot {
+                save_cache_snapshot(&handle.resolver(), path);
+            }
+            info!("Server stopped");
+            log::logger().flush();
+        }
+        Some(("check-config", matches)) => {
+            let path = matches
+                .get_one::<PathBuf>("config")
                 .unwrap_or_else(|| exit_invalid_args!());
-            let blocklist_option = matches.get_one::<PathBuf>("blocklist");
-            server(ip_address, port, blocklist_option);
+            load_config(path);
+            println!("{} is valid", path.display());
         }
         _ => exit_invalid_args!(),
     }
@@ -0,0 +1,121 @@
+/// Limits from RFC 9156 section 2.3 which bound the extra queries minimisation can cause
+const MAX_MINIMISE_COUNT: usize = 10;
+const MINIMISE_ONE_LAB: usize = 4;
+
+/// Progress of QNAME minimisation (RFC 9156) while resolving one name. Each query reveals only
+/// one more label than the zone being asked, until the full name is reached.
+pub struct QnameMinimiser {
+    // Labels of the name known to exist or be delegated, counted from the root
+    revealed: usize,
+    // Labels in the most recent minimised query
+    pending: usize,
+    queries: usize,
+    disabled: bool,
+}
+
+impl QnameMinimiser {
+    pub fn new() -> Self {
+        Self {
+            revealed: 0,
+            pending: 0,
+            queries: 0,
+            disabled: false,
+        }
+    }
+
+    /// Name to ask the servers for `zone` about while resolving `domain_name`, or None when the
+    /// full name should be sent
+    pub fn next_qname(&mut self, domain_name: &str, zone: &str) -> Option<String> {
+        if self.disabled || self.queries >= MAX_MINIMISE_COUNT {
+            return None;
+        }
+        let labels: Vec<&str> = domain_name.split('.').filter(|l| !l.is_empty()).collect();
+        let zone_labels = zone.split('.').filter(|l| !l.is_empty()).count();
+        self.revealed = self.revealed.max(zone_labels);
+        let remaining = labels.len().saturating_sub(self.revealed);
+        let step = if self.queries < MINIMISE_ONE_LAB {
+            1
+        } else {
+            (remaining / (MAX_MINIMISE_COUNT - self.queries)).max(1)
+        };
+        let reveal = self.revealed + step;
+        if reveal >= labels.len() {
+            return None;
+        }
+        self.queries += 1;
+        self.pending = reveal;
+        Some(labels[labels.len() - reveal..].join("."))
+    }
+
+    /// The last minimised name exists without being delegated, so move past it
+    pub fn advance(&mut self) {
+        self.revealed = self.pending;
+    }
+
+    /// Send the full name from now on, for servers which mishandle minimised queries
+    pub fn disable(&mut self) {
+        self.disabled = true;
+    }
+
+    /// Start again for a new name, e.g. the target of a CNAME
+    pub fn restart(&mut self) {
+        self.revealed = 0;
+        self.pending = 0;
+        self.queries = 0;
+    }
+}
+
+impl Default for QnameMinimiser {
+    fn default() -> Self {
+        QnameMinimiser::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn one_label_per_zone() {
+        let mut minimiser = QnameMinimiser::new();
+        let res = minimiser.next_qname("www.example.com", "");
+        assert_eq!(res, Some("com".to_string()));
+        let res = minimiser.next_qname("www.example.com", "com");
+        assert_eq!(res, Some("example.com".to_string()));
+        // the final label is sent with the full query
+        let res = minimiser.next_qname("www.example.com", "example.com");
+        assert_eq!(res, None);
+    }
+    #[test]
+    fn advance_past_non_delegated_name() {
+        let mut minimiser = QnameMinimiser::new();
+        let name = "a.b.example.com";
+        assert_eq!(
+            minimiser.next_qname(name, "example.com"),
+            Some("b.example.com".to_string())
+        );
+        minimiser.advance();
+        assert_eq!(minimiser.next_qname(name, "example.com"), None);
+    }
+    #[test]
+    fn disabled_sends_full_name() {
+        let mut minimiser = QnameMinimiser::new();
+        minimiser.disable();
+        assert_eq!(minimiser.next_qname("www.example.com", ""), None);
+    }
+    #[test]
+    fn bounded_query_count() {
+        let name = (0..30)
+            .map(|i| format!("l{i}"))
+            .collect::<Vec<_>>()
+            .join(".");
+        let mut minimiser = QnameMinimiser::new();
+        let mut queries = 0;
+        while minimiser.next_qname(&name, "").is_some() {
+            minimiser.advance();
+            queries += 1;
+        }
+        assert!(queries <= MAX_MINIMISE_COUNT);
+    }
+}
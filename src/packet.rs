@@ -3,8 +3,15 @@ use crate::header::DnsHeader;
 use crate::question::DnsQuestion;
 use crate::record::{DnsRecord, Rdata};
 use crate::rr_fields::HeaderFlags;
+use crate::util::{is_subdomain, names_equal, names_equal_case_sensitive, normalize_name};
+use log::warn;
+use rand::rngs::OsRng;
+use rand::Rng;
 use std::io::Cursor;
-use std::net::UdpSocket;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
 use std::vec;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -17,6 +24,85 @@ pub struct DnsPacket {
     pub additionals: Vec<DnsRecord>,
 }
 
+/// Source ports to pick from, skipping the well-known and registered ranges
+const EPHEMERAL_PORTS: RangeInclusive<u16> = 1024..=u16::MAX;
+/// Attempts at finding an unused source port before giving up
+const BIND_ATTEMPTS: usize = 10;
+
+/// Settings for a single query sent with `DnsPacket::send_query`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueryOptions {
+    pub recursion_desired: bool,
+    /// Randomize the case of the query name and require the response to echo it exactly (DNS 0x20)
+    pub randomize_case: bool,
+    /// How long to wait for a matching response
+    pub timeout: Duration,
+}
+
+/// A query ready to send, which checks that responses actually answer it
+pub(crate) struct OutgoingQuery {
+    pub bytes: Vec<u8>,
+    id: u16,
+    sent_question: DnsQuestion,
+    name: String,
+    match_case: bool,
+}
+
+impl OutgoingQuery {
+    pub fn new(question: &DnsQuestion, options: &QueryOptions) -> Result<Self, DnsError> {
+        let header = if options.recursion_desired {
+            DnsHeader::recursive_query_header()
+        } else {
+            DnsHeader::simple_query_header()
+        };
+        let sent_question = if options.randomize_case {
+            question.with_random_case()
+        } else {
+            question.clone()
+        };
+        Ok(Self {
+            bytes: DnsPacket::build_query(&header, &sent_question)?,
+            id: header.id,
+            sent_question,
+            name: question.name.clone(),
+            match_case: options.randomize_case,
+        })
+    }
+
+    /// The response in `bytes` with case randomization undone, or None when they aren't a
+    /// response to this query, e.g. because they are spoofed
+    pub fn accept(&self, bytes: &[u8]) -> Option<DnsPacket> {
+        let response = match DnsPacket::from_bytes(bytes) {
+            Ok(response) => response,
+            Err(err) => {
+                warn!("Discarding undecodable response: {:?}", err);
+                return None;
+            }
+        };
+        if !response.answers_query(self.id, &self.sent_question, self.match_case) {
+            warn!("Discarding response which doesn't match the query");
+            return None;
+        }
+        Some(response.with_question_name(&self.name))
+    }
+}
+
+/// Prefix a message with its length, as DNS over TCP requires
+pub(crate) fn tcp_message(bytes: &[u8]) -> Result<Vec<u8>, DnsError> {
+    let length = u16::try_from(bytes.len())
+        .map_err(|_| DnsError::EncodeError("Message too long for TCP"))?;
+    Ok([&length.to_be_bytes()[..], bytes].concat())
+}
+
+pub(crate) fn recv_error(err: std::io::Error) -> DnsError {
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+            DnsError::TimeoutError("Timed out waiting for response")
+        }
+        _ => DnsError::NetworkError("Failed receiving from socket"),
+    }
+}
+
 macro_rules! parse_num_items {
     ($reader: expr, $num: expr, $parser: path) => {{
         let result: Result<Vec<_>, DnsError> = (0..$num).map(|_| $parser($reader)).collect();
@@ -62,8 +148,12 @@ impl DnsPacket {
         Ok(bytes)
     }
 
-    pub fn get_answer(&self) -> Option<&DnsRecord> {
+    /// First answer for `domain_name`, ignoring records for any other name
+    pub fn get_answer(&self, domain_name: &str) -> Option<&DnsRecord> {
         for answer in &self.answers {
+            if !names_equal(&answer.name, domain_name) {
+                continue;
+            }
             match answer.rdata {
                 Rdata::A(_) | Rdata::CNAME(_) | Rdata::AAAA(_) | Rdata::MX(_) => {
                     return Some(answer);
@@ -74,22 +164,11 @@ impl DnsPacket {
         None
     }
 
-    pub fn get_nameserver_ip(&self) -> Option<&str> {
-        for record in &self.additionals {
-            if let Rdata::A(string) = &record.rdata {
-                return Some(string);
-            }
-        }
-        None
-    }
-
-    pub fn get_nameserver(&self) -> Option<&str> {
-        for auth in &self.authorities {
-            if let Rdata::NS(string) = &auth.rdata {
-                return Some(string);
-            }
-        }
-        None
+    /// Drop answers outside `zone`, which the server answering for `zone` has no authority over
+    pub fn retain_answers_in_zone(&mut self, zone: &str) {
+        self.answers
+            .retain(|answer| is_subdomain(&normalize_name(&answer.name), zone));
+        self.header.num_answers = self.answers.len() as u16;
     }
 
     pub fn packet_from_question(question: DnsQuestion) -> DnsPacket {
@@ -103,26 +182,151 @@ impl DnsPacket {
         }
     }
 
-    pub fn build_query(question: &DnsQuestion) -> Result<Vec<u8>, DnsError> {
-        let header = DnsHeader::simple_query_header();
+    pub fn build_query(header: &DnsHeader, question: &DnsQuestion) -> Result<Vec<u8>, DnsError> {
         let mut query_bytes = header.to_bytes()?;
         query_bytes.append(&mut question.to_bytes());
         Ok(query_bytes)
     }
 
-    pub fn send_query(nameserver: &str, question: &DnsQuestion) -> Result<DnsPacket, DnsError> {
+    /// Send `question` to `nameserver` over UDP and wait for a response which answers it
+    pub fn send_query(
+        nameserver: SocketAddr,
+        question: &DnsQuestion,
+        options: QueryOptions,
+    ) -> Result<DnsPacket, DnsError> {
         // TODO different buf size?
         let mut buf: [u8; 1024] = [0; 1024];
-        let query = Self::build_query(question)?;
-        let socket = UdpSocket::bind("0.0.0.0:0")
-            .map_err(|_| DnsError::NetworkError("Failed binding to socket"))?;
+        let query = OutgoingQuery::new(question, &options)?;
+        let socket = Self::bind_random_port(nameserver)?;
         let _res = socket
-            .send_to(&query, (nameserver, 53))
+            .send_to(&query.bytes, nameserver)
+            .map_err(|_| DnsError::NetworkError("Failed sending query"))?;
+        // Keep waiting through datagrams which don't answer this query, as they may be spoofed
+        let deadline = Instant::now() + options.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(DnsError::TimeoutError("Timed out waiting for response"));
+            }
+            socket
+                .set_read_timeout(Some(remaining))
+                .map_err(|_| DnsError::NetworkError("Failed setting socket timeout"))?;
+            let (num_bytes, src_addr) = socket.recv_from(&mut buf).map_err(recv_error)?;
+            if src_addr != nameserver {
+                warn!("Discarding response from unexpected address {}", src_addr);
+                continue;
+            }
+            if let Some(response) = query.accept(&buf[..num_bytes]) {
+                return Ok(response);
+            }
+        }
+    }
+
+    /// Send `question` to `nameserver` over TCP, e.g. after a truncated UDP response
+    pub fn send_query_tcp(
+        nameserver: SocketAddr,
+        question: &DnsQuestion,
+        options: QueryOptions,
+    ) -> Result<DnsPacket, DnsError> {
+        let query = OutgoingQuery::new(question, &options)?;
+        let deadline = Instant::now() + options.timeout;
+        let mut stream =
+            TcpStream::connect_timeout(&nameserver, options.timeout).map_err(|err| {
+                match err.kind() {
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                        DnsError::TimeoutError("Timed out connecting to nameserver")
+                    }
+                    _ => DnsError::NetworkError("Failed connecting to nameserver"),
+                }
+            })?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(DnsError::TimeoutError("Timed out waiting for response"));
+        }
+        stream
+            .set_read_timeout(Some(remaining))
+            .and_then(|_| stream.set_write_timeout(Some(remaining)))
+            .map_err(|_| DnsError::NetworkError("Failed setting socket timeout"))?;
+        stream
+            .write_all(&tcp_message(&query.bytes)?)
             .map_err(|_| DnsError::NetworkError("Failed sending query"))?;
-        let (_num_bytes, _src_addr) = socket
-            .recv_from(&mut buf)
-            .map_err(|_| DnsError::NetworkError("Failed receiving from socket"))?;
-        DnsPacket::from_bytes(&buf)
+        let mut length = [0u8; 2];
+        stream.read_exact(&mut length).map_err(recv_error)?;
+        let mut buf = vec![0u8; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut buf).map_err(recv_error)?;
+        query.accept(&buf).ok_or(DnsError::NetworkError(
+            "Nameserver sent a TCP response which doesn't match the query",
+        ))
+    }
+
+    /// This response with the ID, question and recursion desired flag of another query for the
+    /// same question, so it can be shared between them
+    pub fn for_query(mut self, query: &DnsPacket) -> Self {
+        let rd = HeaderFlags::RD_RECURSION_DESIRED as u16;
+        self.header.id = query.header.id;
+        self.header.flags = (self.header.flags & !rd) | (query.header.flags & rd);
+        self.questions.clone_from(&query.questions);
+        self
+    }
+
+    /// Whether the server truncated this response to fit in a datagram, so it should be
+    /// requested again over TCP
+    pub fn is_truncated(&self) -> bool {
+        self.header.flags & HeaderFlags::TC_TRUNCATED as u16 != 0
+    }
+
+    /// Whether this packet is a response to the query with `id` and `question`.
+    /// With `match_case` the question name must be echoed with exactly the same case.
+    pub fn answers_query(&self, id: u16, question: &DnsQuestion, match_case: bool) -> bool {
+        let is_response = self.header.flags & HeaderFlags::QR_RESPONSE as u16 != 0;
+        let echoed_question = match &self.questions[..] {
+            [echoed] => {
+                let same_name = if match_case {
+                    names_equal_case_sensitive(&echoed.name, &question.name)
+                } else {
+                    names_equal(&echoed.name, &question.name)
+                };
+                same_name && echoed.qtype == question.qtype && echoed.class == question.class
+            }
+            _ => false,
+        };
+        is_response && self.header.id == id && echoed_question
+    }
+
+    /// Undo case randomization by writing `name` back wherever the response echoed the query name
+    fn with_question_name(mut self, name: &str) -> Self {
+        let records = self
+            .answers
+            .iter_mut()
+            .chain(self.authorities.iter_mut())
+            .chain(self.additionals.iter_mut());
+        for record in records {
+            if names_equal(&record.name, name) {
+                record.name = name.to_string();
+            }
+        }
+        for echoed in self.questions.iter_mut() {
+            echoed.name = name.to_string();
+        }
+        self
+    }
+
+    /// Bind to a random source port, which along with the query ID makes responses hard to forge.
+    /// The socket uses the same address family as `nameserver`.
+    pub(crate) fn bind_random_port(nameserver: SocketAddr) -> Result<UdpSocket, DnsError> {
+        let unspecified: IpAddr = match nameserver {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        for _ in 0..BIND_ATTEMPTS {
+            let port = OsRng.gen_range(EPHEMERAL_PORTS);
+            match UdpSocket::bind((unspecified, port)) {
+                Ok(socket) => return Ok(socket),
+                Err(err) if err.kind() == ErrorKind::AddrInUse => continue,
+                Err(_) => break,
+            }
+        }
+        Err(DnsError::NetworkError("Failed binding to socket"))
     }
 
     pub fn make_error_response(self, err: DnsError) -> DnsPacket {
@@ -132,11 +336,41 @@ impl DnsPacket {
             | DnsError::ResolveError(_)
             | DnsError::EncodeError(_)
             | DnsError::NetworkError(_)
+            | DnsError::TimeoutError(_)
+            | DnsError::DeadlineError(_)
+            | DnsError::LimitError(_)
+            | DnsError::ConfigError(_)
             | DnsError::DecodeError(_) => HeaderFlags::RCODE_SERVER_ERR,
         };
+        self.make_rcode_response(error_rcode)
+    }
+
+    /// An answerless REFUSED response, for clients the server won't resolve for
+    pub fn make_refused_response(self) -> DnsPacket {
+        self.make_rcode_response(HeaderFlags::RCODE_REFUSED)
+    }
+
+    fn make_rcode_response(self, rcode: HeaderFlags) -> DnsPacket {
         let mut header = self.header;
         header.flags |= HeaderFlags::QR_RESPONSE as u16;
-        header.flags |= error_rcode as u16;
+        header.flags |= rcode as u16;
+        DnsPacket {
+            header,
+            questions: self.questions,
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        }
+    }
+
+    /// This response cut down to its header and question with the TC flag set, for when it
+    /// doesn't fit in a datagram and the client should ask again over TCP
+    pub fn truncate(self) -> DnsPacket {
+        let mut header = self.header;
+        header.flags |= HeaderFlags::TC_TRUNCATED as u16;
+        header.num_answers = 0;
+        header.num_authorities = 0;
+        header.num_additionals = 0;
         DnsPacket {
             header,
             questions: self.questions,
@@ -153,7 +387,6 @@ mod tests {
     use crate::rr_fields::Class;
     use crate::rr_fields::Type;
     use crate::util::encode_dns_name;
-    use hex;
     use pretty_assertions::assert_eq;
 
     #[test]
@@ -241,9 +474,10 @@ mod tests {
             answers: vec![record.clone()],
         };
 
-        let result = packet.get_answer();
+        let result = packet.get_answer("encrypted-tbn0.gstatic.com");
         let expected = Some(&record);
         assert_eq!(result, expected);
+        assert_eq!(packet.get_answer("www.gstatic.com"), None);
     }
     #[test]
     fn query_example() {
@@ -254,11 +488,93 @@ mod tests {
             qtype: Type::A,
             class: Class::CLASS_IN,
         };
-        let res = DnsPacket::build_query(&question).unwrap();
+        let header = DnsHeader::simple_query_header();
+        let res = DnsPacket::build_query(&header, &question).unwrap();
         let res_hex = hex::encode(res);
         assert_eq!(res_hex[4..], expected[4..]);
     }
     #[test]
+    fn query_recursion_desired() {
+        let question = DnsQuestion {
+            name: "www.example.com".to_string(),
+            qtype: Type::A,
+            class: Class::CLASS_IN,
+        };
+        let header = DnsHeader::recursive_query_header();
+        let res = DnsPacket::build_query(&header, &question).unwrap();
+        assert_eq!(res[2..4], [0x01, 0x00]);
+    }
+    fn response_to(id: u16, question: DnsQuestion) -> DnsPacket {
+        DnsPacket {
+            header: DnsHeader {
+                id,
+                flags: HeaderFlags::QR_RESPONSE as u16,
+                num_questions: 1,
+                num_answers: 0,
+                num_authorities: 0,
+                num_additionals: 0,
+            },
+            questions: vec![question],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        }
+    }
+    #[test]
+    fn answers_matching_query() {
+        let question = DnsQuestion::new("www.example.com", Type::A, Class::CLASS_IN);
+        let response = response_to(0x1234, question.clone());
+        assert!(response.answers_query(0x1234, &question, false));
+    }
+    #[test]
+    fn answers_query_case() {
+        let question = DnsQuestion::new("wWw.ExAmple.cOm", Type::A, Class::CLASS_IN);
+        let response = response_to(
+            0x1234,
+            DnsQuestion::new("www.example.com", Type::A, Class::CLASS_IN),
+        );
+        assert!(response.answers_query(0x1234, &question, false));
+        assert!(!response.answers_query(0x1234, &question, true));
+        let echoed = response_to(0x1234, question.clone());
+        assert!(echoed.answers_query(0x1234, &question, true));
+    }
+    #[test]
+    fn answers_query_mismatch() {
+        let question = DnsQuestion::new("www.example.com", Type::A, Class::CLASS_IN);
+        let wrong_id = response_to(0x4321, question.clone());
+        assert!(!wrong_id.answers_query(0x1234, &question, false));
+        let wrong_name = response_to(
+            0x1234,
+            DnsQuestion::new("evil.example.com", Type::A, Class::CLASS_IN),
+        );
+        assert!(!wrong_name.answers_query(0x1234, &question, false));
+        let wrong_type = response_to(
+            0x1234,
+            DnsQuestion::new("www.example.com", Type::MX, Class::CLASS_IN),
+        );
+        assert!(!wrong_type.answers_query(0x1234, &question, false));
+        let mut not_response = response_to(0x1234, question.clone());
+        not_response.header.flags = 0;
+        assert!(!not_response.answers_query(0x1234, &question, false));
+    }
+    #[test]
+    fn truncate_keeps_question() {
+        let question = DnsQuestion::new("www.example.com", Type::A, Class::CLASS_IN);
+        let mut response = response_to(0x1234, question.clone());
+        response.answers = vec![DnsRecord {
+            name: "www.example.com".to_string(),
+            class: Class::CLASS_IN,
+            ttl: 300,
+            rdata: Rdata::A("93.184.216.34".to_string()),
+        }];
+        response.header.num_answers = 1;
+        let truncated = response.truncate();
+        assert!(truncated.is_truncated());
+        assert!(truncated.answers_query(0x1234, &question, false));
+        assert_eq!(truncated.answers, vec![]);
+        assert_eq!(truncated.header.num_answers, 0);
+    }
+    #[test]
     fn test_encode_dns_name() {
         let expected = String::from("03777777076578616d706c6503636f6d00");
         let res = encode_dns_name("www.example.com");
@@ -19,6 +19,14 @@ impl DnsQuestion {
         }
     }
 
+    /// Copy of the question with the case of its name randomized (DNS 0x20)
+    pub fn with_random_case(&self) -> Self {
+        Self {
+            name: util::randomize_case(&self.name),
+            ..self.clone()
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = util::encode_dns_name(&self.name);
         bytes.extend_from_slice(&(self.qtype as u16).to_be_bytes());
@@ -44,7 +52,6 @@ impl DnsQuestion {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use hex;
     use pretty_assertions::assert_eq;
     use std::io::Cursor;
     #[test]
@@ -0,0 +1,385 @@
+use crate::cidr::{Cidr, CidrTable};
+use crate::clock::{Clock, SystemClock};
+use crate::packet::DnsPacket;
+use crate::rr_fields::Type;
+use crate::util::{lock, normalize_name};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Clients or responses tracked before those whose buckets refill soonest are forgotten
+const MAX_TRACKED: usize = 100_000;
+/// Clients whose responses are limited together, as in BIND
+const RRL_IPV4_PREFIX: u8 = 24;
+const RRL_IPV6_PREFIX: u8 = 56;
+const DEFAULT_SLIP: u32 = 2;
+
+/// Rate limits for the clients in one range. None means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientLimits {
+    /// Queries each client may send per second
+    pub queries_per_second: Option<u32>,
+    /// Queries a client may send at once after being idle, at least `queries_per_second`
+    pub burst: u32,
+    /// Identical responses (same name, type and response code) sent per second to each
+    /// client network, a /24 for IPv4 or /56 for IPv6. Only applies to UDP, where source
+    /// addresses can be spoofed to aim responses at a victim.
+    pub responses_per_second: Option<u32>,
+    /// Every `slip`th response over the limit is sent truncated instead of being dropped, so
+    /// real clients caught by the limit can retry over TCP. 0 drops all of them.
+    pub slip: u32,
+}
+
+impl Default for ClientLimits {
+    fn default() -> Self {
+        Self {
+            queries_per_second: None,
+            burst: 0,
+            responses_per_second: None,
+            slip: DEFAULT_SLIP,
+        }
+    }
+}
+
+/// Rate limits by client range, using the most specific range containing a client
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimits {
+    ranges: CidrTable<ClientLimits>,
+    // For clients outside every range
+    default: ClientLimits,
+}
+
+impl RateLimits {
+    pub fn new(default: ClientLimits) -> Self {
+        Self {
+            ranges: CidrTable::new(),
+            default,
+        }
+    }
+
+    /// Add limits for a range, replacing any existing limits for the same range
+    pub fn add(&mut self, cidr: Cidr, limits: ClientLimits) {
+        self.ranges.add(cidr, limits);
+    }
+
+    pub fn with_range(mut self, cidr: Cidr, limits: ClientLimits) -> Self {
+        self.add(cidr, limits);
+        self
+    }
+
+    /// The limits for clients outside every range
+    pub fn with_default(mut self, default: ClientLimits) -> Self {
+        self.default = default;
+        self
+    }
+
+    pub fn default_limits(&self) -> &ClientLimits {
+        &self.default
+    }
+
+    pub fn limits_for(&self, ip: IpAddr) -> &ClientLimits {
+        self.ranges.lookup(ip).unwrap_or(&self.default)
+    }
+}
+
+/// What to do with a response under response rate limiting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseAction {
+    Send,
+    /// Send it truncated, with no records, so the client retries over TCP
+    Truncate,
+    Drop,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+    // When the bucket will have refilled, after which it can be forgotten
+    full_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(now: Instant, capacity: f64) -> Self {
+        Self {
+            tokens: capacity,
+            updated: now,
+            full_at: now,
+        }
+    }
+
+    /// Refill at `rate` tokens per second up to `capacity`, then take a token if there is one
+    fn take(&mut self, now: Instant, rate: f64, capacity: f64) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.updated = now;
+        let taken = self.tokens >= 1.0;
+        if taken {
+            self.tokens -= 1.0;
+        }
+        self.full_at = now + Duration::from_secs_f64((capacity - self.tokens) / rate);
+        taken
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct ResponseKey {
+    network: Cidr,
+    name: String,
+    qtype: Type,
+    rcode: u16,
+}
+
+struct Tracked<V> {
+    bucket: TokenBucket,
+    // Tells apart buckets which refill at the same instant
+    id: u64,
+    state: V,
+}
+
+/// Token buckets by key, holding at most `MAX_TRACKED` so spoofed sources can't grow it without
+/// bound. When full, the bucket which refills soonest is forgotten.
+struct Buckets<K, V> {
+    buckets: HashMap<K, Tracked<V>>,
+    // Keys in order of when their buckets refill
+    refills: BTreeMap<(Instant, u64), K>,
+    next_id: u64,
+}
+
+impl<K: Clone + Eq + Hash, V: Default> Buckets<K, V> {
+    fn new() -> Self {
+        Self {
+            buckets: HashMap::new(),
+            refills: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Take a token from the bucket for `key`, which starts full if it isn't tracked. Also
+    /// returns the state kept alongside the bucket.
+    fn take(&mut self, key: K, now: Instant, rate: f64, capacity: f64) -> (bool, &mut V) {
+        if !self.buckets.contains_key(&key) {
+            while self.buckets.len() >= MAX_TRACKED {
+                let Some((_, forgotten)) = self.refills.pop_first() else {
+                    break;
+                };
+                self.buckets.remove(&forgotten);
+            }
+        }
+        let next_id = &mut self.next_id;
+        let tracked = self.buckets.entry(key.clone()).or_insert_with(|| {
+            *next_id += 1;
+            Tracked {
+                bucket: TokenBucket::new(now, capacity),
+                id: *next_id,
+                state: V::default(),
+            }
+        });
+        self.refills.remove(&(tracked.bucket.full_at, tracked.id));
+        let taken = tracked.bucket.take(now, rate, capacity);
+        self.refills
+            .insert((tracked.bucket.full_at, tracked.id), key);
+        (taken, &mut tracked.state)
+    }
+}
+
+/// Applies `RateLimits` to queries and responses as they pass through the server
+pub struct RateLimiter {
+    limits: Mutex<RateLimits>,
+    clients: Mutex<Buckets<IpAddr, ()>>,
+    // Each bucket is kept with the number of responses over the limit since it was last
+    // under, for slip
+    responses: Mutex<Buckets<ResponseKey, u32>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits: Mutex::new(limits),
+            clients: Mutex::new(Buckets::new()),
+            responses: Mutex::new(Buckets::new()),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Apply new limits, keeping the state of every client's buckets
+    pub fn set_limits(&self, limits: RateLimits) {
+        *lock(&self.limits) = limits;
+    }
+
+    /// Whether a query from `ip` is within its client's query rate
+    pub fn allow_query(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let limits = *lock(&self.limits).limits_for(ip);
+        let Some(rate) = limits.queries_per_second else {
+            return true;
+        };
+        let rate = f64::from(rate.max(1));
+        let capacity = f64::from(limits.burst).max(rate);
+        let now = self.clock.now();
+        lock(&self.clients).take(ip, now, rate, capacity).0
+    }
+
+    /// Whether to send `response` to `ip` over UDP, truncate it or drop it, so the server
+    /// can't be used to flood a spoofed address with responses
+    pub fn response_action(&self, ip: IpAddr, response: &DnsPacket) -> ResponseAction {
+        let ip = ip.to_canonical();
+        let limits = *lock(&self.limits).limits_for(ip);
+        let Some(rate) = limits.responses_per_second else {
+            return ResponseAction::Send;
+        };
+        let Some(question) = response.questions.first() else {
+            return ResponseAction::Send;
+        };
+        let prefix_len = match ip {
+            IpAddr::V4(_) => RRL_IPV4_PREFIX,
+            IpAddr::V6(_) => RRL_IPV6_PREFIX,
+        };
+        let Some(network) = Cidr::new(ip, prefix_len) else {
+            return ResponseAction::Send;
+        };
+        let key = ResponseKey {
+            network,
+            name: normalize_name(&question.name),
+            qtype: question.qtype,
+            rcode: response.header.rcode(),
+        };
+        let rate = f64::from(rate.max(1));
+        let now = self.clock.now();
+        let mut responses = lock(&self.responses);
+        let (taken, over_limit) = responses.take(key, now, rate, rate);
+        if taken {
+            *over_limit = 0;
+            return ResponseAction::Send;
+        }
+        *over_limit = over_limit.saturating_add(1);
+        match limits.slip {
+            0 => ResponseAction::Drop,
+            slip if *over_limit % slip == 0 => ResponseAction::Truncate,
+            _ => ResponseAction::Drop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::question::DnsQuestion;
+    use crate::rr_fields::Class;
+    use pretty_assertions::assert_eq;
+    use std::net::Ipv4Addr;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn limiter(limits: RateLimits) -> (RateLimiter, MockClock) {
+        let clock = MockClock::new();
+        (
+            RateLimiter::new(limits).with_clock(Arc::new(clock.clone())),
+            clock,
+        )
+    }
+
+    #[test]
+    fn query_token_bucket() {
+        let (limiter, clock) = limiter(RateLimits::new(ClientLimits {
+            queries_per_second: Some(2),
+            burst: 4,
+            ..ClientLimits::default()
+        }));
+        let allowed = (0..6)
+            .filter(|_| limiter.allow_query(ip("10.0.0.1")))
+            .count();
+        assert_eq!(allowed, 4);
+        // Other clients have their own bucket, including IPv4-mapped forms of other addresses
+        assert!(limiter.allow_query(ip("::ffff:10.0.0.2")));
+        clock.advance(Duration::from_millis(500));
+        assert!(limiter.allow_query(ip("::ffff:10.0.0.1")));
+        assert!(!limiter.allow_query(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn limits_by_range() {
+        let (limiter, _) = limiter(RateLimits::default().with_range(
+            "192.168.1.50".parse().unwrap(),
+            ClientLimits {
+                queries_per_second: Some(1),
+                ..ClientLimits::default()
+            },
+        ));
+        assert!(limiter.allow_query(ip("192.168.1.50")));
+        assert!(!limiter.allow_query(ip("192.168.1.50")));
+        assert!((0..100).all(|_| limiter.allow_query(ip("192.168.1.51"))));
+    }
+
+    #[test]
+    fn response_rate_limit_slips() {
+        let (limiter, clock) = limiter(RateLimits::new(ClientLimits {
+            responses_per_second: Some(2),
+            slip: 2,
+            ..ClientLimits::default()
+        }));
+        let question = DnsQuestion::new("example.com", Type::A, Class::CLASS_IN);
+        let response = DnsPacket::packet_from_question(question);
+        // Neighbours in the same /24 share the limit
+        let actions: Vec<ResponseAction> = ["198.51.100.1", "198.51.100.2"]
+            .iter()
+            .cycle()
+            .take(6)
+            .map(|client| limiter.response_action(ip(client), &response))
+            .collect();
+        use ResponseAction::*;
+        assert_eq!(actions, vec![Send, Send, Drop, Truncate, Drop, Truncate]);
+        let other = DnsQuestion::new("example.org", Type::A, Class::CLASS_IN);
+        let other = DnsPacket::packet_from_question(other);
+        assert_eq!(limiter.response_action(ip("198.51.100.1"), &other), Send);
+        assert_eq!(limiter.response_action(ip("198.51.101.1"), &response), Send);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.response_action(ip("198.51.100.1"), &response), Send);
+    }
+
+    #[test]
+    fn tracked_clients_bounded() {
+        let (limiter, clock) = limiter(RateLimits::new(ClientLimits {
+            queries_per_second: Some(1),
+            ..ClientLimits::default()
+        }));
+        let client = |n: usize| IpAddr::from(Ipv4Addr::from(n as u32));
+        for n in 0..MAX_TRACKED + 10 {
+            assert!(limiter.allow_query(client(n)));
+        }
+        clock.advance(Duration::from_millis(500));
+        {
+            let clients = lock(&limiter.clients);
+            assert_eq!(clients.buckets.len(), MAX_TRACKED);
+            assert_eq!(clients.refills.len(), MAX_TRACKED);
+        }
+        // The clients whose buckets refill soonest were forgotten, so they start with a full one
+        assert!(limiter.allow_query(client(0)));
+        assert!(!limiter.allow_query(client(MAX_TRACKED + 9)));
+    }
+
+    #[test]
+    fn set_limits_keeps_buckets() {
+        let (limiter, _) = limiter(RateLimits::new(ClientLimits {
+            queries_per_second: Some(1),
+            ..ClientLimits::default()
+        }));
+        assert!(limiter.allow_query(ip("10.0.0.1")));
+        limiter.set_limits(RateLimits::new(ClientLimits {
+            queries_per_second: Some(1),
+            burst: 5,
+            ..ClientLimits::default()
+        }));
+        assert!(!limiter.allow_query(ip("10.0.0.1")));
+    }
+}
@@ -1,10 +1,11 @@
-use crate::cursor_read_num;
 use crate::error::DnsError;
 use crate::question::DnsQuestion;
 use crate::rr_fields::{Class, Type};
 use crate::util::{decode_dns_name, encode_dns_name};
+use std::fmt;
 use std::io::{Cursor, Read};
 use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 
 #[derive(Debug, PartialEq, Clone)]
 #[allow(dead_code)]
@@ -135,6 +136,69 @@ impl DnsRecord {
     }
 }
 
+/// Zone file presentation, e.g. `example.com 300 IN A 192.0.2.1`
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} IN {:?} ", self.name, self.ttl, self.get_type())?;
+        match &self.rdata {
+            Rdata::A(string) | Rdata::NS(string) | Rdata::CNAME(string) | Rdata::AAAA(string) => {
+                write!(f, "{}", string)
+            }
+            Rdata::SOA(soa) => write!(
+                f,
+                "{} {} {} {} {} {}",
+                soa.mname, soa.rname, soa.serial, soa.refresh, soa.retry, soa.expire
+            ),
+            Rdata::MX(mx) => write!(f, "{} {}", mx.preference, mx.exchange),
+        }
+    }
+}
+
+impl FromStr for DnsRecord {
+    type Err = DnsError;
+    /// Parse a record in the zone file layout that `Display` writes, e.g.
+    /// `example.com 300 IN A 192.0.2.1`. A trailing dot on names is optional.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || DnsError::DecodeError(format!("Invalid record: {}", s));
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [name, ttl, class, rtype, ref data @ ..] = fields[..] else {
+            return Err(invalid());
+        };
+        if !class.eq_ignore_ascii_case("IN") {
+            return Err(invalid());
+        }
+        let name_field = |field: &str| field.trim_end_matches('.').to_string();
+        let number = |field: &str| field.parse().map_err(|_| invalid());
+        let rdata = match (rtype.parse::<Type>()?, data) {
+            (Type::A, [ip]) => Rdata::A(ip.parse::<Ipv4Addr>().map_err(|_| invalid())?.to_string()),
+            (Type::AAAA, [ip]) => {
+                Rdata::AAAA(ip.parse::<Ipv6Addr>().map_err(|_| invalid())?.to_string())
+            }
+            (Type::NS, [target]) => Rdata::NS(name_field(target)),
+            (Type::CNAME, [target]) => Rdata::CNAME(name_field(target)),
+            (Type::MX, [preference, exchange]) => Rdata::MX(RdataMX {
+                preference: u16::from_str(preference).map_err(|_| invalid())?,
+                exchange: name_field(exchange),
+            }),
+            (Type::SOA, [mname, rname, serial, refresh, retry, expire]) => Rdata::SOA(RdataSOA {
+                mname: name_field(mname),
+                rname: name_field(rname),
+                serial: number(serial)?,
+                refresh: number(refresh)?,
+                retry: number(retry)?,
+                expire: number(expire)?,
+            }),
+            _ => return Err(invalid()),
+        };
+        Ok(DnsRecord {
+            name: name_field(name),
+            class: Class::CLASS_IN,
+            ttl: number(ttl)?,
+            rdata,
+        })
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct RdataSOA {
     pub mname: String,
@@ -207,6 +271,46 @@ mod tests {
     use crate::rr_fields::Class;
     use pretty_assertions::assert_eq;
     #[test]
+    fn test_display_record() {
+        let record = DnsRecord {
+            name: String::from("example.com"),
+            class: Class::CLASS_IN,
+            ttl: 300,
+            rdata: Rdata::A(String::from("192.0.2.1")),
+        };
+        assert_eq!(record.to_string(), "example.com 300 IN A 192.0.2.1");
+    }
+    #[test]
+    fn test_parse_record() {
+        let record: DnsRecord = "example.com. 300 IN MX 10 mail.example.com."
+            .parse()
+            .unwrap();
+        assert_eq!(
+            record.to_string(),
+            "example.com 300 IN MX 10 mail.example.com"
+        );
+        let soa = ". 86400 IN SOA a.root-servers.net nstld.verisign-grs.com 1 1800 900 604800";
+        let record: DnsRecord = soa.parse().unwrap();
+        assert_eq!(record.name, "");
+        assert_eq!(record.to_string(), soa.trim_start_matches('.'));
+        assert!("example.com 300 IN A 300.0.0.1"
+            .parse::<DnsRecord>()
+            .is_err());
+        assert!("example.com 300 CH A 192.0.2.1"
+            .parse::<DnsRecord>()
+            .is_err());
+    }
+    #[test]
+    fn test_parse_record_mx_preference_out_of_range() {
+        let res = "example.com 300 IN MX 70000 mail.example.com".parse::<DnsRecord>();
+        assert_eq!(
+            res,
+            Err(DnsError::DecodeError(
+                "Invalid record: example.com 300 IN MX 70000 mail.example.com".to_string()
+            ))
+        );
+    }
+    #[test]
     fn test_from_bytes_record_aaaa() {
         let packet_hex = "a15e818000010001000000020377777706676f6f676c6503636f6d0000410001c00c00\
         41000100001bb6000d00010000010006026832026833c00c000100010000005200048efa5024c00c001c0001000000\
@@ -1,23 +1,450 @@
-use crate::cache::DnsCache;
+pub use crate::cache::CacheLimits;
+use crate::cache::{DelegationCache, DnsCache};
+use crate::clock::{Clock, SystemClock};
+use crate::coalesce::{InFlight, Joined};
+use crate::delegation::{Delegation, DNS_PORT};
 use crate::error::DnsError;
+use crate::forward::ForwardZones;
 use crate::header::DnsHeader;
-use crate::packet::DnsPacket;
+use crate::local::LocalRecords;
+use crate::minimise::QnameMinimiser;
+use crate::packet::{DnsPacket, QueryOptions};
 use crate::question::DnsQuestion;
 use crate::record::{DnsRecord, Rdata};
+use crate::root_hints::RootHints;
 use crate::rr_fields::{Class, HeaderFlags, Type};
-use log::{debug, info};
+use crate::rtt::RttTable;
+use crate::trace::{ResponseSummary, Trace, TraceEvent, TraceStep};
+use crate::transport::{BlockingTransport, Transport};
+use crate::util::{block_on, lock, normalize_name};
+use log::{debug, info, warn};
 use std::collections::HashSet;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufReader, BufWriter};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+/// Where the resolver sends queries it can't answer from the cache
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpstreamMode {
+    /// Iterate from the root nameservers
+    Recursive,
+    /// Send recursion-desired queries to upstream resolvers, trying each in order until one answers
+    Forward(Vec<SocketAddr>),
+}
+
+/// Which address families nameservers are queried over, and which is tried first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpPreference {
+    #[default]
+    Ipv4First,
+    Ipv6First,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl IpPreference {
+    /// Drop addresses of a disallowed family and move the preferred family to the front,
+    /// otherwise keeping the order of `addrs`
+    pub fn apply(self, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+        let (ipv4, ipv6): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            addrs.iter().partition(|addr| addr.is_ipv4());
+        match self {
+            IpPreference::Ipv4First => [ipv4, ipv6].concat(),
+            IpPreference::Ipv6First => [ipv6, ipv4].concat(),
+            IpPreference::Ipv4Only => ipv4,
+            IpPreference::Ipv6Only => ipv6,
+        }
+    }
+
+    /// Record types to look up for a nameserver's address, in order of preference
+    fn address_types(self) -> &'static [Type] {
+        match self {
+            IpPreference::Ipv4First => &[Type::A, Type::AAAA],
+            IpPreference::Ipv6First => &[Type::AAAA, Type::A],
+            IpPreference::Ipv4Only => &[Type::A],
+            IpPreference::Ipv6Only => &[Type::AAAA],
+        }
+    }
+}
+
+impl FromStr for IpPreference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ipv4-first" => Ok(IpPreference::Ipv4First),
+            "ipv6-first" => Ok(IpPreference::Ipv6First),
+            "ipv4-only" => Ok(IpPreference::Ipv4Only),
+            "ipv6-only" => Ok(IpPreference::Ipv6Only),
+            _ => Err(format!(
+                "Invalid IP preference {}, expected ipv4-first, ipv6-first, ipv4-only or ipv6-only",
+                s
+            )),
+        }
+    }
+}
+
+/// How long to wait for upstream servers and how often to retry them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timeouts {
+    /// Time to wait for a response to a single query before trying the next server
+    pub query: Duration,
+    /// Number of extra rounds through servers which timed out, doubling the timeout each round
+    pub retries: u32,
+    /// Total time allowed to resolve one request, including nested nameserver lookups
+    pub resolution: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            query: Duration::from_millis(800),
+            retries: 2,
+            resolution: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Bounds on the work done for one request, so loops and misconfigured zones can't keep the
+/// resolver busy indefinitely
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    /// Number of CNAMEs which can be followed for one name
+    pub cname_chain: usize,
+    /// Number of referrals which can be followed for one name
+    pub referrals: usize,
+    /// How deeply nameserver names without glue can be resolved within each other
+    pub nameserver_depth: usize,
+    /// Number of queries sent upstream for one request, including nested lookups
+    pub queries: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            cname_chain: 8,
+            referrals: 30,
+            nameserver_depth: 4,
+            queries: 100,
+        }
+    }
+}
+
+/// How names on the blocklist are answered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlockResponse {
+    /// `0.0.0.0` for A queries and `::` for AAAA, with no answers for other types
+    #[default]
+    NullAddress,
+    /// NXDOMAIN, as if the name didn't exist
+    NxDomain,
+}
+
+impl FromStr for BlockResponse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "null-address" => Ok(BlockResponse::NullAddress),
+            "nxdomain" => Ok(BlockResponse::NxDomain),
+            _ => Err(format!(
+                "Invalid block response {}, expected null-address or nxdomain",
+                s
+            )),
+        }
+    }
+}
+
+/// Answers for blocklisted names
+#[derive(Debug, Clone, PartialEq)]
+pub struct Blocking {
+    pub response: BlockResponse,
+    /// TTL of null address answers, so clients don't ask again straight away
+    pub ttl: u32,
+}
+
+impl Default for Blocking {
+    fn default() -> Self {
+        Self {
+            response: BlockResponse::default(),
+            ttl: 43200,
+        }
+    }
+}
+
+type Lookup<'a> = Pin<Box<dyn Future<Output = Result<DnsPacket, DnsError>> + Send + 'a>>;
+
+/// Seconds since the Unix epoch, which unlike `Instant` is comparable across restarts
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// State for resolving a single request, shared with any nested nameserver lookups
+struct Resolution {
+    started: Instant,
+    deadline: Instant,
+    queries: usize,
+    nameserver_depth: usize,
+    // Steps taken so far, when the caller asked for a trace
+    trace: Option<Vec<TraceStep>>,
+}
+
+impl Resolution {
+    fn new(started: Instant, timeout: Duration) -> Self {
+        Self {
+            started,
+            deadline: started + timeout,
+            queries: 0,
+            nameserver_depth: 0,
+            trace: None,
+        }
+    }
+
+    /// Add a step to the trace, only building the event when tracing
+    fn record(&mut self, now: Instant, event: impl FnOnce() -> TraceEvent) {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push(TraceStep {
+                elapsed: now.saturating_duration_since(self.started),
+                depth: self.nameserver_depth,
+                event: event(),
+            });
+        }
+    }
+
+    fn remaining(&self, now: Instant) -> Result<Duration, DnsError> {
+        let remaining = self.deadline.saturating_duration_since(now);
+        if remaining.is_zero() {
+            return Err(DnsError::DeadlineError(
+                "Resolution deadline exceeded".to_string(),
+            ));
+        }
+        Ok(remaining)
+    }
+
+    fn count_query(&mut self, limit: usize) -> Result<(), DnsError> {
+        if self.queries >= limit {
+            return Err(DnsError::LimitError(format!(
+                "Sent the maximum of {} upstream queries",
+                limit
+            )));
+        }
+        self.queries += 1;
+        Ok(())
+    }
+}
+
+/// Resolves queries from any number of threads at once. The caches are shared between threads
+/// and locks are never held across network I/O.
 pub struct Resolver {
-    cache: DnsCache,
+    cache: Mutex<DnsCache>,
+    delegations: Mutex<DelegationCache>,
+    rtt: Mutex<RttTable>,
     blocklist: HashSet<String>,
+    // Names never blocked, even when they're on the blocklist
+    allowlist: HashSet<String>,
+    blocking: Blocking,
+    local_records: LocalRecords,
+    cache_limits: CacheLimits,
+    clock: Arc<dyn Clock>,
+    upstream_mode: UpstreamMode,
+    forward_zones: ForwardZones,
+    root_hints: Mutex<RootHints>,
+    timeouts: Timeouts,
+    // Servers exempt from DNS 0x20, or None when it's disabled
+    case_randomization: Option<HashSet<IpAddr>>,
+    qname_minimisation: bool,
+    limits: Limits,
+    ip_preference: IpPreference,
+    // Port for nameservers learned from referrals, which only give their IPs
+    nameserver_port: u16,
+    transport: Arc<dyn Transport>,
+    in_flight: InFlight,
 }
 
 impl Resolver {
     pub fn new(blocklist: HashSet<String>) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
         Resolver {
-            cache: DnsCache::new(),
+            cache: Mutex::new(DnsCache::with_clock(clock.clone())),
+            delegations: Mutex::new(DelegationCache::with_clock(clock.clone())),
+            rtt: Mutex::new(RttTable::with_clock(clock.clone())),
             blocklist,
+            allowlist: HashSet::new(),
+            blocking: Blocking::default(),
+            local_records: LocalRecords::new(),
+            cache_limits: CacheLimits::default(),
+            clock,
+            upstream_mode: UpstreamMode::Recursive,
+            forward_zones: ForwardZones::new(),
+            root_hints: Mutex::new(RootHints::default()),
+            timeouts: Timeouts::default(),
+            case_randomization: None,
+            qname_minimisation: false,
+            limits: Limits::default(),
+            ip_preference: IpPreference::default(),
+            nameserver_port: DNS_PORT,
+            transport: Arc::new(BlockingTransport),
+            in_flight: InFlight::new(),
+        }
+    }
+
+    pub fn with_upstream_mode(mut self, upstream_mode: UpstreamMode) -> Self {
+        self.upstream_mode = upstream_mode;
+        self
+    }
+
+    /// Forward queries for names at or below `suffix` to `upstreams`, regardless of upstream mode.
+    /// When several suffixes match a name, the longest one wins.
+    pub fn with_forward_zone(mut self, suffix: &str, upstreams: Vec<SocketAddr>) -> Self {
+        self.forward_zones.add(suffix, upstreams);
+        self
+    }
+
+    /// Use `clock` for all time-dependent state, e.g. a `MockClock` in tests.
+    /// Replaces the caches, so call this before resolving anything.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.cache =
+            Mutex::new(DnsCache::with_clock(clock.clone()).with_limits(self.cache_limits.clone()));
+        self.delegations = Mutex::new(DelegationCache::with_clock(clock.clone()));
+        self.rtt = Mutex::new(RttTable::with_clock(clock.clone()));
+        self.clock = clock;
+        self
+    }
+
+    /// Bound the answer cache. Replaces the cache, so call this before resolving anything.
+    pub fn with_cache_limits(mut self, limits: CacheLimits) -> Self {
+        self.cache =
+            Mutex::new(DnsCache::with_clock(self.clock.clone()).with_limits(limits.clone()));
+        self.cache_limits = limits;
+        self
+    }
+
+    pub fn with_blocking(mut self, blocking: Blocking) -> Self {
+        self.blocking = blocking;
+        self
+    }
+
+    /// Exceptions to the blocklist, e.g. for names a blocklist file catches by mistake
+    pub fn with_allowlist(mut self, allowlist: HashSet<String>) -> Self {
+        self.allowlist = allowlist;
+        self
+    }
+
+    /// Answer names in `local_records` from them, ahead of the blocklist and upstreams
+    pub fn with_local_records(mut self, local_records: LocalRecords) -> Self {
+        self.local_records = local_records;
+        self
+    }
+
+    pub fn with_root_hints(mut self, root_hints: RootHints) -> Self {
+        self.root_hints = Mutex::new(root_hints);
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Randomize the case of outgoing query names and discard responses which don't echo it
+    /// exactly (DNS 0x20). Servers in `exempt` are known to mangle case and are queried as is.
+    pub fn with_case_randomization(mut self, exempt: HashSet<IpAddr>) -> Self {
+        self.case_randomization = Some(exempt);
+        self
+    }
+
+    /// Ask a root from the hints for the current root NS set (RFC 8109) and use it in place of
+    /// the hints. On failure the hints are kept, so callers can log the error and carry on.
+    pub fn prime(&self) -> Result<(), DnsError> {
+        block_on(self.prime_async())
+    }
+
+    pub(crate) async fn prime_async(&self) -> Result<(), DnsError> {
+        let mut resolution = Resolution::new(self.clock.now(), self.timeouts.resolution);
+        let root_delegation = lock(&self.root_hints).delegation();
+        let roots = self.nameservers_to_query(&root_delegation);
+        // Every root is on the same port, which the primed addresses keep
+        let port = roots
+            .first()
+            .ok_or_else(|| DnsError::ResolveError("No root servers available".to_string()))?
+            .port();
+        info!("Priming root servers");
+        let question = DnsQuestion::new("", Type::NS, Class::CLASS_IN);
+        let response = self
+            .query_servers(&roots, &question, false, &mut resolution)
+            .await?;
+        let root_hints = RootHints::from_priming_response(&response, port)?;
+        debug!("Primed {} root servers", root_hints.nameservers().len());
+        *lock(&self.root_hints) = root_hints;
+        Ok(())
+    }
+
+    /// Send queries with `transport`, e.g. one driven by an async runtime, or a `MockTransport`
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_ip_preference(mut self, ip_preference: IpPreference) -> Self {
+        self.ip_preference = ip_preference;
+        self
+    }
+
+    /// Query nameservers found through referrals on `port` instead of 53, e.g. to recurse through
+    /// test servers which can't bind a privileged port
+    pub fn with_nameserver_port(mut self, port: u16) -> Self {
+        self.nameserver_port = port;
+        self
+    }
+
+    /// Only reveal one more label of the query name to each zone's servers (RFC 9156).
+    /// Falls back to the full name when servers mishandle minimised queries.
+    pub fn with_qname_minimisation(mut self, enabled: bool) -> Self {
+        self.qname_minimisation = enabled;
+        self
+    }
+
+    fn blocked_response(
+        &self,
+        header: DnsHeader,
+        question: &DnsQuestion,
+    ) -> Result<DnsPacket, DnsError> {
+        let null_address = match question.qtype {
+            Type::A => Some(Rdata::A("0.0.0.0".to_string())),
+            Type::AAAA => Some(Rdata::AAAA("::".to_string())),
+            _ => None,
+        };
+        match self.blocking.response {
+            BlockResponse::NullAddress => {
+                let answers = null_address
+                    .map(|rdata| DnsRecord {
+                        name: question.name.clone(),
+                        class: Class::CLASS_IN,
+                        ttl: self.blocking.ttl,
+                        rdata,
+                    })
+                    .into_iter()
+                    .collect();
+                Self::build_response(header, question, answers)
+            }
+            BlockResponse::NxDomain => {
+                let mut packet = Self::build_response(header, question, vec![])?;
+                packet.header.flags |= HeaderFlags::RCODE_NAME_ERR as u16;
+                Ok(packet)
+            }
         }
     }
 
@@ -47,10 +474,94 @@ impl Resolver {
         })
     }
 
-    pub fn resolve_packet(&mut self, query_packet: DnsPacket) -> Result<DnsPacket, DnsError> {
-        // Verisign root nameserver
-        let root_nameserver = String::from("198.41.0.4");
-        let mut nameserver = root_nameserver;
+    /// Answer a request from local records alone, or None when it would need resolving
+    pub fn resolve_local(&self, query_packet: &DnsPacket) -> Option<DnsPacket> {
+        let question = query_packet.questions.first()?;
+        let records = self.local_records.lookup(question)?;
+        Self::build_response(query_packet.header.clone(), question, records).ok()
+    }
+
+    /// Write the unexpired cached answers to `path`, returning how many were written
+    pub fn save_cache(&self, path: &Path) -> io::Result<usize> {
+        let records = BufWriter::new(File::create(path)?);
+        lock(&self.cache).write_snapshot(records, unix_time())
+    }
+
+    /// Cache the answers saved to `path` by `save_cache`, less the time since they were saved.
+    /// Returns how many were still live.
+    pub fn load_cache(&self, path: &Path) -> io::Result<usize> {
+        let records = BufReader::new(File::open(path)?);
+        lock(&self.cache).read_snapshot(records, unix_time())
+    }
+
+    /// Cache the unexpired answers cached by `other`, e.g. when it's replaced by a resolver with
+    /// new settings
+    pub fn copy_cache_from(&self, other: &Resolver) {
+        let records = lock(&other.cache).records();
+        let mut cache = lock(&self.cache);
+        for record in records {
+            if let Err(err) = cache.add(&record) {
+                warn!("Failed to copy cached record: {:?}", err);
+            }
+        }
+    }
+
+    pub fn resolve_packet(&self, query_packet: DnsPacket) -> Result<DnsPacket, DnsError> {
+        block_on(self.resolve_packet_async(query_packet))
+    }
+
+    /// Resolve a request, sharing one resolution between identical concurrent requests so
+    /// only one set of queries for a question is ever outstanding
+    pub(crate) async fn resolve_packet_async(
+        &self,
+        query_packet: DnsPacket,
+    ) -> Result<DnsPacket, DnsError> {
+        let Some(question) = query_packet.questions.first() else {
+            return self.resolve_uncoalesced(query_packet).await;
+        };
+        let key = DnsQuestion::new(
+            &normalize_name(&question.name),
+            question.qtype,
+            question.class,
+        );
+        loop {
+            match self.in_flight.join(key.clone()) {
+                Joined::Leader(guard) => {
+                    let result = self.resolve_uncoalesced(query_packet).await;
+                    guard.finish(&result);
+                    return result;
+                }
+                Joined::Follower(flight) => {
+                    debug!("Waiting for in-flight resolution of {}", key.name);
+                    if let Some(result) = flight.wait().await {
+                        return result.map(|response| response.for_query(&query_packet));
+                    }
+                }
+            }
+        }
+    }
+
+    async fn resolve_uncoalesced(&self, query_packet: DnsPacket) -> Result<DnsPacket, DnsError> {
+        let mut resolution = Resolution::new(self.clock.now(), self.timeouts.resolution);
+        self.resolve_packet_within(query_packet, &mut resolution)
+            .await
+    }
+
+    /// Resolve within the budget of `resolution`. Boxed because looking up the address of a
+    /// nameserver without glue recurses back into here.
+    fn resolve_packet_within<'a>(
+        &'a self,
+        query_packet: DnsPacket,
+        resolution: &'a mut Resolution,
+    ) -> Lookup<'a> {
+        Box::pin(self.resolve_packet_unboxed(query_packet, resolution))
+    }
+
+    async fn resolve_packet_unboxed(
+        &self,
+        query_packet: DnsPacket,
+        resolution: &mut Resolution,
+    ) -> Result<DnsPacket, DnsError> {
         // Assuming there is only 1 question as RFC 1035 says this is typical.
         let orig_question = query_packet.questions.first().ok_or_else(|| {
             DnsError::ResolveError("Invalid request: no question supplied".to_string())
@@ -58,35 +569,102 @@ impl Resolver {
         let mut domain_name = orig_question.name.clone();
         let record_type = orig_question.qtype;
         let mut answers: Vec<DnsRecord> = vec![];
-        if self.blocklist.contains(&domain_name) {
+        if let Some(records) = self.local_records.lookup(orig_question) {
+            debug!("Answering {} from local records", domain_name);
+            return Self::build_response(query_packet.header, orig_question, records);
+        }
+        if self.blocklist.contains(&domain_name) && !self.allowlist.contains(&domain_name) {
             debug!("Blocklisted domain: {}", domain_name);
-            let loopback_record = DnsRecord {
-                name: domain_name,
-                class: Class::CLASS_IN,
-                ttl: 43200,
-                rdata: Rdata::A("0.0.0.0".to_string()),
-            };
-            answers.push(loopback_record);
-            let response = Self::build_response(query_packet.header, orig_question, answers);
-            return response;
+            resolution.record(self.clock.now(), || TraceEvent::Blocked {
+                name: domain_name.clone(),
+            });
+            return self.blocked_response(query_packet.header, orig_question);
+        }
+        if let Some(zone) = self.forward_zones.lookup(&domain_name) {
+            debug!(
+                "Domain {} matches forward zone {}",
+                domain_name, zone.suffix
+            );
+            let upstreams = zone.upstreams.clone();
+            return self
+                .forward(query_packet.header, orig_question, &upstreams, resolution)
+                .await;
         }
+        if let UpstreamMode::Forward(upstreams) = &self.upstream_mode {
+            let upstreams = upstreams.clone();
+            return self
+                .forward(query_packet.header, orig_question, &upstreams, resolution)
+                .await;
+        }
+        let mut delegation = self.closest_delegation(&domain_name);
+        let mut minimiser = self.qname_minimisation.then(QnameMinimiser::new);
+        let mut chain: HashSet<String> = HashSet::from([normalize_name(&domain_name)]);
+        let mut referrals = 0;
         loop {
-            info!("Querying {} for {}", nameserver, domain_name);
             let question = DnsQuestion::new(&domain_name, record_type, Class::CLASS_IN);
             // check cache
-            if let Some(record) = self.cache.lookup(&question) {
+            let cached = lock(&self.cache).lookup(&question).cloned();
+            if let Some(record) = cached {
                 debug!("Cache hit");
-                answers.push(record.clone());
+                resolution.record(self.clock.now(), || TraceEvent::CacheHit {
+                    record: record.clone(),
+                });
+                answers.push(record);
                 let response = Self::build_response(query_packet.header, orig_question, answers);
                 return response;
             }
             debug!("Cache miss");
+            let minimised_name = minimiser
+                .as_mut()
+                .and_then(|minimiser| minimiser.next_qname(&domain_name, &delegation.zone));
+            if let (Some(minimiser), Some(name)) = (minimiser.as_mut(), minimised_name) {
+                // RFC 9156 recommends type A for queries before the final one
+                let minimised = DnsQuestion::new(&name, Type::A, Class::CLASS_IN);
+                let servers = self.nameservers_to_query(&delegation);
+                match self
+                    .query_servers(&servers, &minimised, false, resolution)
+                    .await
+                {
+                    Ok(response) => {
+                        let rcode = response.header.rcode();
+                        if let Some(referral) =
+                            Delegation::from_referral(&response, &delegation.zone, &domain_name)
+                        {
+                            self.count_referral(&mut referrals, &domain_name)?;
+                            delegation = self.follow_referral(referral, resolution).await?;
+                        } else if rcode == 0 {
+                            // the name exists inside this zone, so reveal another label
+                            minimiser.advance();
+                        } else {
+                            // relaxed mode: some servers answer NXDOMAIN for empty non-terminals
+                            debug!(
+                                "Minimised query for {} returned rcode {}. Sending full name.",
+                                name, rcode
+                            );
+                            minimiser.disable();
+                        }
+                    }
+                    Err(err @ DnsError::DeadlineError(_)) => return Err(err),
+                    Err(err) => {
+                        debug!(
+                            "Minimised query for {} failed with error {:?}. Sending full name.",
+                            name, err
+                        );
+                        minimiser.disable();
+                    }
+                }
+                continue;
+            }
             // otherwise ask remote resolver
-            let response = DnsPacket::send_query(&nameserver, &question)?;
-            self.cache.cache_answers(&response)?;
-            if let Some(answer) = response.get_answer() {
+            let servers = self.nameservers_to_query(&delegation);
+            let mut response = self
+                .query_servers(&servers, &question, false, resolution)
+                .await?;
+            response.retain_answers_in_zone(&delegation.zone);
+            lock(&self.cache).cache_answers(&response)?;
+            if let Some(answer) = response.get_answer(&domain_name) {
                 match &answer.rdata {
-                    Rdata::A(string) => {
+                    Rdata::A(string) | Rdata::AAAA(string) => {
                         let answer_string = string;
                         debug!("Got ip: {}", answer_string);
                         answers.push(answer.clone());
@@ -97,8 +675,29 @@ impl Resolver {
                     Rdata::CNAME(string) => {
                         let answer_string = string;
                         debug!("Got CNAME domain: {}", answer_string);
+                        if !chain.insert(normalize_name(answer_string)) {
+                            return Err(DnsError::LimitError(format!(
+                                "CNAME loop detected at {}",
+                                answer_string
+                            )));
+                        }
+                        if chain.len() > self.limits.cname_chain + 1 {
+                            return Err(DnsError::LimitError(format!(
+                                "CNAME chain for {} is longer than {}",
+                                orig_question.name, self.limits.cname_chain
+                            )));
+                        }
+                        resolution.record(self.clock.now(), || TraceEvent::Cname {
+                            name: domain_name.clone(),
+                            target: answer_string.clone(),
+                        });
                         answers.push(answer.clone());
                         domain_name = answer_string.clone();
+                        // the target may be in any zone, so start again from the closest one
+                        delegation = self.closest_delegation(&domain_name);
+                        if let Some(minimiser) = minimiser.as_mut() {
+                            minimiser.restart();
+                        }
                     }
                     Rdata::MX(rdata_mx) => {
                         debug!("Got MX record: {}", rdata_mx.exchange);
@@ -114,12 +713,19 @@ impl Resolver {
                         )))
                     }
                 }
-            } else if let Some(ns_ip) = response.get_nameserver_ip() {
-                debug!("Got nameserver ip: {}", ns_ip);
-                nameserver = ns_ip.to_string();
-            } else if let Some(ns_domain) = response.get_nameserver() {
-                debug!("Got nameserver domain: {}", ns_domain);
-                nameserver = self.resolve(ns_domain, Type::A)?; // TODO is Type A right?
+            } else if let Some(referral) =
+                Delegation::from_referral(&response, &delegation.zone, &domain_name)
+            {
+                self.count_referral(&mut referrals, &domain_name)?;
+                delegation = self.follow_referral(referral, resolution).await?;
+            } else if response.header.rcode() == HeaderFlags::RCODE_NAME_ERR as u16 {
+                debug!("Domain {} doesn't exist", domain_name);
+                let mut packet = Self::build_response(query_packet.header, orig_question, answers)?;
+                packet.header.flags |= HeaderFlags::RCODE_NAME_ERR as u16;
+                return Ok(packet);
+            } else if Self::is_nodata(&response) {
+                debug!("Domain {} has no {:?} records", domain_name, record_type);
+                return Self::build_response(query_packet.header, orig_question, answers);
             } else {
                 return Err(DnsError::ResolveError(format!(
                     "Unexpected response: {:?}",
@@ -129,16 +735,241 @@ impl Resolver {
         }
     }
 
-    pub fn resolve(
-        &mut self,
-        req_domain_name: &str,
-        record_type: Type,
-    ) -> Result<String, DnsError> {
-        // Verisign root nameserver
-        let question = DnsQuestion::new(req_domain_name, record_type, Class::CLASS_IN);
-        let query_packet = DnsPacket::packet_from_question(question);
-        let response_packet = self.resolve_packet(query_packet)?;
-        for answer in response_packet.answers {
+    fn count_referral(&self, referrals: &mut usize, domain_name: &str) -> Result<(), DnsError> {
+        if *referrals >= self.limits.referrals {
+            return Err(DnsError::LimitError(format!(
+                "Followed more than {} referrals for {}",
+                self.limits.referrals, domain_name
+            )));
+        }
+        *referrals += 1;
+        Ok(())
+    }
+
+    /// A successful response without answers, which isn't a referral either
+    fn is_nodata(response: &DnsPacket) -> bool {
+        response.header.rcode() == 0
+            && response
+                .authorities
+                .iter()
+                .all(|record| !matches!(record.rdata, Rdata::NS(_)))
+    }
+
+    /// Make the nameservers of a referral usable and remember them for later lookups
+    async fn follow_referral(
+        &self,
+        mut referral: Delegation,
+        resolution: &mut Resolution,
+    ) -> Result<Delegation, DnsError> {
+        debug!(
+            "Got referral to {} with nameservers {:?}",
+            referral.zone, referral.nameservers
+        );
+        // Referrals only give nameserver IPs, so glue is reached on the configured port
+        for addr in referral
+            .nameservers
+            .iter_mut()
+            .flat_map(|ns| ns.addrs.iter_mut())
+        {
+            addr.set_port(self.nameserver_port);
+        }
+        resolution.record(self.clock.now(), || TraceEvent::Referral {
+            zone: referral.zone.clone(),
+            nameservers: referral
+                .nameservers
+                .iter()
+                .map(|ns| ns.name.clone())
+                .collect(),
+        });
+        if self.ip_preference.apply(&referral.addrs()).is_empty() {
+            self.resolve_glueless(&mut referral, resolution).await?;
+        }
+        lock(&self.delegations).add(&referral)?;
+        Ok(referral)
+    }
+
+    /// Usable addresses of a delegation's nameservers, fastest first within each address family
+    fn nameservers_to_query(&self, delegation: &Delegation) -> Vec<SocketAddr> {
+        self.ip_preference
+            .apply(&lock(&self.rtt).order(&delegation.addrs()))
+    }
+
+    /// The cached delegation closest to `domain_name`, or the root when there's none
+    fn closest_delegation(&self, domain_name: &str) -> Delegation {
+        let cached = lock(&self.delegations).closest(domain_name).cloned();
+        match cached {
+            Some(delegation) => {
+                debug!("Starting from cached delegation for {}", delegation.zone);
+                delegation
+            }
+            None => lock(&self.root_hints).delegation(),
+        }
+    }
+
+    /// Resolve an address for the first nameserver of a referral without usable glue, trying
+    /// each allowed address family in order of preference
+    async fn resolve_glueless(
+        &self,
+        referral: &mut Delegation,
+        resolution: &mut Resolution,
+    ) -> Result<(), DnsError> {
+        if resolution.nameserver_depth >= self.limits.nameserver_depth {
+            return Err(DnsError::LimitError(format!(
+                "Nameserver lookups for {} nested more than {} deep",
+                referral.zone, self.limits.nameserver_depth
+            )));
+        }
+        let mut last_err = DnsError::ResolveError("Referral has no nameservers".to_string());
+        for ns in referral.nameservers.iter_mut() {
+            for &record_type in self.ip_preference.address_types() {
+                let question = DnsQuestion::new(&ns.name, record_type, Class::CLASS_IN);
+                let query_packet = DnsPacket::packet_from_question(question);
+                resolution.nameserver_depth += 1;
+                let result = self
+                    .resolve_packet_within(query_packet, resolution)
+                    .await
+                    .and_then(|response| Self::first_answer(response, record_type));
+                resolution.nameserver_depth -= 1;
+                match result {
+                    Ok(ns_ip) => {
+                        ns.addrs.push(self.nameserver_addr(&ns_ip)?);
+                        return Ok(());
+                    }
+                    Err(err @ (DnsError::DeadlineError(_) | DnsError::LimitError(_))) => {
+                        return Err(err)
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Failed to resolve {:?} for nameserver {}: {:?}",
+                            record_type, ns.name, err
+                        );
+                        last_err = err;
+                    }
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Send `question` to each server in turn until one gives a usable response.
+    /// Servers which time out are retried with double the timeout, up to the configured retries.
+    async fn query_servers(
+        &self,
+        servers: &[SocketAddr],
+        question: &DnsQuestion,
+        recursion_desired: bool,
+        resolution: &mut Resolution,
+    ) -> Result<DnsPacket, DnsError> {
+        let mut last_err = DnsError::ResolveError("No nameservers to query".to_string());
+        let mut candidates = servers.to_vec();
+        let mut timeout = self.timeouts.query;
+        for _attempt in 0..=self.timeouts.retries {
+            let mut timed_out = vec![];
+            for server in candidates {
+                let remaining = resolution.remaining(self.clock.now())?;
+                resolution.count_query(self.limits.queries)?;
+                info!("Querying {} for {}", server, question.name);
+                let options = QueryOptions {
+                    recursion_desired,
+                    randomize_case: self.randomizes_case_for(server),
+                    timeout: timeout.min(remaining),
+                };
+                let sent = self.clock.now();
+                let result = self.transport.query(server, question, options).await;
+                let now = self.clock.now();
+                let rtt = now.saturating_duration_since(sent);
+                match &result {
+                    Ok(_) => lock(&self.rtt).record(server, rtt),
+                    Err(DnsError::TimeoutError(_)) => {
+                        lock(&self.rtt).record_timeout(server, timeout)
+                    }
+                    Err(_) => {}
+                }
+                resolution.record(now, || TraceEvent::Query {
+                    server,
+                    question: question.clone(),
+                    rtt,
+                    outcome: result
+                        .as_ref()
+                        .map(ResponseSummary::new)
+                        .map_err(Clone::clone),
+                });
+                match result {
+                    Ok(response) if Self::is_server_failure(&response) => {
+                        let rcode = response.header.rcode();
+                        warn!("Server {} returned rcode {}", server, rcode);
+                        last_err = DnsError::ResolveError(format!(
+                            "Server {} returned rcode {}",
+                            server, rcode
+                        ));
+                    }
+                    Ok(response) => return Ok(response),
+                    Err(err @ DnsError::TimeoutError(_)) => {
+                        debug!("Server {} timed out after {:?}", server, timeout);
+                        timed_out.push(server);
+                        last_err = err;
+                    }
+                    Err(err) => {
+                        warn!("Server {} failed with error {:?}", server, err);
+                        last_err = err;
+                    }
+                }
+            }
+            if timed_out.is_empty() {
+                break;
+            }
+            candidates = timed_out;
+            timeout = timeout.saturating_mul(2);
+        }
+        Err(last_err)
+    }
+
+    fn randomizes_case_for(&self, server: SocketAddr) -> bool {
+        self.case_randomization
+            .as_ref()
+            .is_some_and(|exempt| !exempt.contains(&server.ip()))
+    }
+
+    fn is_server_failure(response: &DnsPacket) -> bool {
+        let rcode = response.header.rcode();
+        rcode == HeaderFlags::RCODE_SERVER_ERR as u16 || rcode == HeaderFlags::RCODE_REFUSED as u16
+    }
+
+    async fn forward(
+        &self,
+        header: DnsHeader,
+        question: &DnsQuestion,
+        upstreams: &[SocketAddr],
+        resolution: &mut Resolution,
+    ) -> Result<DnsPacket, DnsError> {
+        let cached = lock(&self.cache).lookup(question).cloned();
+        if let Some(record) = cached {
+            debug!("Cache hit");
+            resolution.record(self.clock.now(), || TraceEvent::CacheHit {
+                record: record.clone(),
+            });
+            return Self::build_response(header, question, vec![record]);
+        }
+        debug!("Cache miss");
+        let response = self
+            .query_servers(upstreams, question, true, resolution)
+            .await?;
+        let rcode = response.header.rcode();
+        lock(&self.cache).cache_answers(&response)?;
+        let mut packet = Self::build_response(header, question, response.answers)?;
+        packet.header.flags |= rcode;
+        Ok(packet)
+    }
+
+    fn nameserver_addr(&self, nameserver: &str) -> Result<SocketAddr, DnsError> {
+        let ip: IpAddr = nameserver.parse().map_err(|_| {
+            DnsError::ResolveError(format!("Invalid nameserver address: {}", nameserver))
+        })?;
+        Ok(SocketAddr::new(ip, self.nameserver_port))
+    }
+
+    fn first_answer(response: DnsPacket, record_type: Type) -> Result<String, DnsError> {
+        for answer in response.answers {
             if answer.get_type() == record_type {
                 match answer.rdata {
                     Rdata::A(string) => return Ok(string),
@@ -153,9 +984,60 @@ impl Resolver {
             record_type
         )))
     }
+
+    /// Resolve like `resolve`, returning every step taken along with the result
+    pub fn trace(&self, req_domain_name: &str, record_type: Type) -> Trace {
+        block_on(self.trace_async(req_domain_name, record_type))
+    }
+
+    pub(crate) async fn trace_async(&self, req_domain_name: &str, record_type: Type) -> Trace {
+        let question = DnsQuestion::new(req_domain_name, record_type, Class::CLASS_IN);
+        let query_packet = DnsPacket::packet_from_question(question);
+        let mut resolution = Resolution::new(self.clock.now(), self.timeouts.resolution);
+        resolution.trace = Some(vec![]);
+        let result = self
+            .resolve_packet_within(query_packet, &mut resolution)
+            .await;
+        Trace {
+            steps: resolution.trace.unwrap_or_default(),
+            result,
+        }
+    }
+
+    pub fn resolve(&self, req_domain_name: &str, record_type: Type) -> Result<String, DnsError> {
+        block_on(self.resolve_async(req_domain_name, record_type))
+    }
+
+    pub(crate) async fn resolve_async(
+        &self,
+        req_domain_name: &str,
+        record_type: Type,
+    ) -> Result<String, DnsError> {
+        let question = DnsQuestion::new(req_domain_name, record_type, Class::CLASS_IN);
+        let query_packet = DnsPacket::packet_from_question(question);
+        let response_packet = self.resolve_packet_async(query_packet).await?;
+        Self::first_answer(response_packet, record_type)
+    }
 }
 impl Default for Resolver {
     fn default() -> Self {
         Self::new(HashSet::new())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn ip_preference_orders_families() {
+        let v4: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::53]:53".parse().unwrap();
+        assert_eq!(IpPreference::Ipv4First.apply(&[v6, v4]), vec![v4, v6]);
+        assert_eq!(IpPreference::Ipv6First.apply(&[v4, v6]), vec![v6, v4]);
+        assert_eq!(IpPreference::Ipv4Only.apply(&[v4, v6]), vec![v4]);
+        assert_eq!(IpPreference::Ipv6Only.apply(&[v4, v6]), vec![v6]);
+        assert_eq!("ipv6-first".parse(), Ok(IpPreference::Ipv6First));
+    }
+}
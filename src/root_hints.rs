@@ -0,0 +1,272 @@
+use crate::delegation::{Delegation, NameServer, DNS_PORT};
+use crate::error::DnsError;
+use crate::packet::DnsPacket;
+use crate::record::Rdata;
+use crate::util::normalize_name;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+/// TTL of the root NS records in the published root hints
+const ROOT_TTL: u32 = 3600000;
+
+/// The 13 root servers as published by IANA, used when no hints file is given
+const DEFAULT_ROOT_SERVERS: [(&str, &str, &str); 13] = [
+    ("a.root-servers.net", "198.41.0.4", "2001:503:ba3e::2:30"),
+    ("b.root-servers.net", "170.247.170.2", "2801:1b8:10::b"),
+    ("c.root-servers.net", "192.33.4.12", "2001:500:2::c"),
+    ("d.root-servers.net", "199.7.91.13", "2001:500:2d::d"),
+    ("e.root-servers.net", "192.203.230.10", "2001:500:a8::e"),
+    ("f.root-servers.net", "192.5.5.241", "2001:500:2f::f"),
+    ("g.root-servers.net", "192.112.36.4", "2001:500:12::d0d"),
+    ("h.root-servers.net", "198.97.190.53", "2001:500:1::53"),
+    ("i.root-servers.net", "192.36.148.17", "2001:7fe::53"),
+    ("j.root-servers.net", "192.58.128.30", "2001:503:c27::2:30"),
+    ("k.root-servers.net", "193.0.14.129", "2001:7fd::1"),
+    ("l.root-servers.net", "199.7.83.42", "2001:500:9f::42"),
+    ("m.root-servers.net", "202.12.27.33", "2001:dc3::35"),
+];
+
+/// Nameservers for the root zone where iterative resolution starts
+#[derive(Debug, Clone, PartialEq)]
+pub struct RootHints {
+    nameservers: Vec<NameServer>,
+}
+
+impl RootHints {
+    pub fn new(nameservers: Vec<NameServer>) -> Result<Self, DnsError> {
+        if nameservers.iter().all(|ns| ns.addrs.is_empty()) {
+            return Err(DnsError::ResolveError(
+                "Root hints don't contain any addresses".to_string(),
+            ));
+        }
+        Ok(Self { nameservers })
+    }
+
+    /// Hints with one unnamed root per address, e.g. a mock root on loopback in tests
+    pub fn from_addrs(addrs: Vec<SocketAddr>) -> Result<Self, DnsError> {
+        let nameservers = addrs
+            .into_iter()
+            .map(|addr| NameServer {
+                name: addr.ip().to_string(),
+                addrs: vec![addr],
+            })
+            .collect();
+        Self::new(nameservers)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, DnsError> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            DnsError::ResolveError(format!("Failed to read root hints file: {}", err))
+        })?;
+        Self::from_named_root(&contents)
+    }
+
+    /// Parse hints in the `named.root` zone file format distributed by IANA
+    pub fn from_named_root(contents: &str) -> Result<Self, DnsError> {
+        let mut nameservers: Vec<NameServer> = vec![];
+        for line in contents.lines() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            // <name> [ttl] [class] <type> <rdata>
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [name, .., rtype, rdata] = fields[..] else {
+                return Err(DnsError::ResolveError(format!(
+                    "Invalid root hints line: {}",
+                    line
+                )));
+            };
+            let name = normalize_name(name);
+            match rtype.to_ascii_uppercase().as_str() {
+                "NS" if name.is_empty() => {
+                    let ns_name = normalize_name(rdata);
+                    if !nameservers.iter().any(|ns| ns.name == ns_name) {
+                        nameservers.push(NameServer {
+                            name: ns_name,
+                            addrs: vec![],
+                        });
+                    }
+                }
+                "A" | "AAAA" => {
+                    let ip: IpAddr = rdata.parse().map_err(|_| {
+                        DnsError::ResolveError(format!("Invalid root hints address: {}", rdata))
+                    })?;
+                    if let Some(ns) = nameservers.iter_mut().find(|ns| ns.name == name) {
+                        ns.addrs.push(SocketAddr::new(ip, DNS_PORT));
+                    }
+                }
+                _ => continue,
+            }
+        }
+        Self::new(nameservers)
+    }
+
+    /// Build hints from the response to a `. NS` priming query (RFC 8109).
+    /// Addresses use `port`, the port of the root which answered.
+    pub fn from_priming_response(response: &DnsPacket, port: u16) -> Result<Self, DnsError> {
+        let mut nameservers: Vec<NameServer> = response
+            .answers
+            .iter()
+            .filter(|record| normalize_name(&record.name).is_empty())
+            .filter_map(|record| match &record.rdata {
+                Rdata::NS(ns_name) => Some(NameServer {
+                    name: normalize_name(ns_name),
+                    addrs: vec![],
+                }),
+                _ => None,
+            })
+            .collect();
+        for record in &response.additionals {
+            let ip: Option<IpAddr> = match &record.rdata {
+                Rdata::A(string) | Rdata::AAAA(string) => string.parse().ok(),
+                _ => None,
+            };
+            let name = normalize_name(&record.name);
+            if let (Some(ip), Some(ns)) = (ip, nameservers.iter_mut().find(|ns| ns.name == name)) {
+                ns.addrs.push(SocketAddr::new(ip, port));
+            }
+        }
+        Self::new(nameservers)
+    }
+
+    pub fn nameservers(&self) -> &[NameServer] {
+        &self.nameservers
+    }
+
+    /// The root zone's delegation, where iterative resolution starts
+    pub fn delegation(&self) -> Delegation {
+        Delegation {
+            zone: String::new(),
+            nameservers: self.nameservers.clone(),
+            ttl: ROOT_TTL,
+        }
+    }
+
+    /// All root addresses in random order, so load is spread across the roots
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.delegation().addrs()
+    }
+}
+
+impl Default for RootHints {
+    fn default() -> Self {
+        let nameservers = DEFAULT_ROOT_SERVERS
+            .iter()
+            .map(|(name, ipv4, ipv6)| NameServer {
+                name: name.to_string(),
+                addrs: [ipv4, ipv6]
+                    .iter()
+                    .filter_map(|ip| ip.parse::<IpAddr>().ok())
+                    .map(|ip| SocketAddr::new(ip, DNS_PORT))
+                    .collect(),
+            })
+            .collect();
+        Self { nameservers }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::DnsHeader;
+    use crate::record::DnsRecord;
+    use crate::rr_fields::Class;
+    use pretty_assertions::assert_eq;
+
+    const NAMED_ROOT: &str = "\
+;       This file holds the information on root name servers needed to
+;       initialize cache of Internet domain name servers
+;
+.                        3600000      NS    A.ROOT-SERVERS.NET.
+A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4
+A.ROOT-SERVERS.NET.      3600000      AAAA  2001:503:ba3e::2:30
+;
+; FORMERLY NS1.ISI.EDU
+;
+.                        3600000      NS    B.ROOT-SERVERS.NET.
+B.ROOT-SERVERS.NET.      3600000  IN  A     170.247.170.2
+; End of file";
+
+    #[test]
+    fn parse_named_root() {
+        let hints = RootHints::from_named_root(NAMED_ROOT).unwrap();
+        let expected = vec![
+            NameServer {
+                name: "a.root-servers.net".to_string(),
+                addrs: vec![
+                    "198.41.0.4:53".parse().unwrap(),
+                    "[2001:503:ba3e::2:30]:53".parse().unwrap(),
+                ],
+            },
+            NameServer {
+                name: "b.root-servers.net".to_string(),
+                addrs: vec!["170.247.170.2:53".parse().unwrap()],
+            },
+        ];
+        assert_eq!(hints.nameservers(), expected);
+    }
+    #[test]
+    fn parse_named_root_without_addresses() {
+        let res = RootHints::from_named_root(".  3600000  NS  A.ROOT-SERVERS.NET.");
+        assert!(res.is_err());
+    }
+    #[test]
+    fn default_hints_have_all_roots() {
+        let hints = RootHints::default();
+        assert_eq!(hints.nameservers().len(), 13);
+        assert!(hints.nameservers().iter().all(|ns| ns.addrs.len() == 2));
+    }
+    #[test]
+    fn addrs_from_addrs() {
+        let addr: SocketAddr = "127.0.0.1:5353".parse().unwrap();
+        let hints = RootHints::from_addrs(vec![addr]).unwrap();
+        assert_eq!(hints.addrs(), vec![addr]);
+    }
+    #[test]
+    fn priming_response() {
+        let ns_record = |ns: &str| DnsRecord {
+            name: "".to_string(),
+            class: Class::CLASS_IN,
+            ttl: 518400,
+            rdata: Rdata::NS(ns.to_string()),
+        };
+        let response = DnsPacket {
+            header: DnsHeader::simple_query_header(),
+            questions: vec![],
+            answers: vec![
+                ns_record("a.root-servers.net"),
+                ns_record("b.root-servers.net"),
+            ],
+            authorities: vec![],
+            additionals: vec![
+                DnsRecord {
+                    name: "a.root-servers.net".to_string(),
+                    class: Class::CLASS_IN,
+                    ttl: 518400,
+                    rdata: Rdata::A("198.41.0.4".to_string()),
+                },
+                // glue for a server not in the NS set is ignored
+                DnsRecord {
+                    name: "evil.example.com".to_string(),
+                    class: Class::CLASS_IN,
+                    ttl: 518400,
+                    rdata: Rdata::A("192.0.2.1".to_string()),
+                },
+            ],
+        };
+        let hints = RootHints::from_priming_response(&response, 5353).unwrap();
+        let expected = vec![
+            NameServer {
+                name: "a.root-servers.net".to_string(),
+                addrs: vec!["198.41.0.4:5353".parse().unwrap()],
+            },
+            NameServer {
+                name: "b.root-servers.net".to_string(),
+                addrs: vec![],
+            },
+        ];
+        assert_eq!(hints.nameservers(), expected);
+    }
+}
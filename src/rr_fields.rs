@@ -1,4 +1,5 @@
 use crate::error::DnsError;
+use std::str::FromStr;
 /// Enums with values for DNS Resource Record (RR) fields
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
 pub enum Type {
@@ -38,6 +39,27 @@ impl TryFrom<u16> for Type {
     }
 }
 
+impl FromStr for Type {
+    type Err = DnsError;
+    /// Parse a type mnemonic such as `AAAA`, ignoring case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "A" => Ok(Type::A),
+            "NS" => Ok(Type::NS),
+            "CNAME" => Ok(Type::CNAME),
+            "SOA" => Ok(Type::SOA),
+            "WKS" => Ok(Type::WKS),
+            "PTR" => Ok(Type::PTR),
+            "HINFO" => Ok(Type::HINFO),
+            "MINFO" => Ok(Type::MINFO),
+            "MX" => Ok(Type::MX),
+            "TXT" => Ok(Type::TXT),
+            "AAAA" => Ok(Type::AAAA),
+            _ => Err(DnsError::DecodeError(format!("Unknown RR Type: {}", s))),
+        }
+    }
+}
+
 // allow non-camel case to match the DNS name for these values
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
 #[allow(non_camel_case_types)]
@@ -60,11 +82,16 @@ impl TryFrom<u16> for Class {
 // TODO use a bitfield/bitmask library
 #[allow(non_camel_case_types)]
 pub enum HeaderFlags {
-    QR_RESPONSE = 0x8000,      // Query on 0, Response on 1
-    RCODE_FORMAT_ERR = 0x0001, // Failed to interpret format
-    RCODE_SERVER_ERR = 0x0002, // Server failure
-    RCODE_NOT_IMPL = 0x0004,   // Not Implemented
-    RCODE_REFUSED = 0x0005,    // Refused
+    QR_RESPONSE = 0x8000,          // Query on 0, Response on 1
+    TC_TRUNCATED = 0x0200,         // Response was cut short to fit in a datagram
+    RD_RECURSION_DESIRED = 0x0100, // Ask the server to resolve recursively
+    RA_RECURSION_AVAIL = 0x0080,   // Server supports recursive queries
+    RCODE_MASK = 0x000f,           // Bits holding the response code
+    RCODE_FORMAT_ERR = 0x0001,     // Failed to interpret format
+    RCODE_SERVER_ERR = 0x0002,     // Server failure
+    RCODE_NAME_ERR = 0x0003,       // Domain name doesn't exist
+    RCODE_NOT_IMPL = 0x0004,       // Not Implemented
+    RCODE_REFUSED = 0x0005,        // Refused
 }
 
 #[cfg(test)]
@@ -72,9 +99,15 @@ mod tests {
     use super::*;
     use pretty_assertions::assert_eq;
     #[test]
+    fn test_from_str() {
+        assert_eq!("aaaa".parse::<Type>(), Ok(Type::AAAA));
+        assert_eq!("MX".parse::<Type>(), Ok(Type::MX));
+        assert!("ANY".parse::<Type>().is_err());
+    }
+    #[test]
     fn test_try_from() {
         let vals = [1, 2, 5, 15, 16, 28];
-        let converted = vals.map(|x| Type::try_from(x));
+        let converted = vals.map(Type::try_from);
         let expected = [
             Ok(Type::A),
             Ok(Type::NS),
@@ -0,0 +1,180 @@
+use crate::clock::{Clock, SystemClock};
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Weight of the previous SRTT when a new sample arrives, as in BIND
+const SRTT_WEIGHT: f64 = 0.7;
+/// Time for an unused server's SRTT to halve, so slow or failed servers are tried again
+const DECAY_HALF_LIFE: Duration = Duration::from_secs(300);
+/// Upper bound for a penalized SRTT
+const MAX_SRTT: Duration = Duration::from_secs(10);
+/// Chance of sending a query to a slower server instead of the fastest one
+const PROBE_CHANCE: f64 = 0.05;
+
+struct RttEntry {
+    srtt: Duration,
+    updated: Instant,
+}
+
+impl RttEntry {
+    /// The SRTT decayed for the time since it was last updated
+    fn decayed(&self, now: Instant) -> Duration {
+        let idle = now.saturating_duration_since(self.updated);
+        let halvings = idle.as_secs_f64() / DECAY_HALF_LIFE.as_secs_f64();
+        self.srtt.mul_f64(0.5f64.powf(halvings))
+    }
+}
+
+/// Smoothed round trip times of nameservers, used to send queries to the fastest ones first
+pub struct RttTable {
+    entries: HashMap<SocketAddr, RttEntry>,
+    clock: Arc<dyn Clock>,
+    probe_chance: f64,
+}
+
+impl RttTable {
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            entries: HashMap::new(),
+            clock,
+            probe_chance: PROBE_CHANCE,
+        }
+    }
+
+    /// Current SRTT of `server`, or None when it hasn't been measured
+    pub fn srtt(&self, server: SocketAddr) -> Option<Duration> {
+        let now = self.clock.now();
+        self.entries.get(&server).map(|entry| entry.decayed(now))
+    }
+
+    /// Fold a measured response time into the SRTT of `server`
+    pub fn record(&mut self, server: SocketAddr, rtt: Duration) {
+        let srtt = match self.srtt(server) {
+            Some(srtt) => srtt.mul_f64(SRTT_WEIGHT) + rtt.mul_f64(1.0 - SRTT_WEIGHT),
+            None => rtt,
+        };
+        self.update(server, srtt);
+    }
+
+    /// Penalize `server` for not answering within `timeout`
+    pub fn record_timeout(&mut self, server: SocketAddr, timeout: Duration) {
+        let srtt = self.srtt(server).unwrap_or_default().saturating_mul(2);
+        self.update(server, srtt.max(timeout).min(MAX_SRTT));
+    }
+
+    fn update(&mut self, server: SocketAddr, srtt: Duration) {
+        let updated = self.clock.now();
+        self.entries.insert(server, RttEntry { srtt, updated });
+    }
+
+    /// `servers` sorted by SRTT, fastest first. Unmeasured servers come first so each gets
+    /// measured, and occasionally a slower server is moved to the front to refresh its SRTT.
+    /// Servers with equal SRTT keep their relative order.
+    pub fn order(&self, servers: &[SocketAddr]) -> Vec<SocketAddr> {
+        let now = self.clock.now();
+        let mut ordered = servers.to_vec();
+        ordered.sort_by_key(|server| {
+            self.entries
+                .get(server)
+                .map(|entry| entry.decayed(now))
+                .unwrap_or_default()
+        });
+        let mut rng = rand::thread_rng();
+        if ordered.len() > 1 && rng.gen_bool(self.probe_chance) {
+            let probe = rng.gen_range(1..ordered.len());
+            let server = ordered.remove(probe);
+            ordered.insert(0, server);
+        }
+        ordered
+    }
+}
+
+impl Default for RttTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use pretty_assertions::assert_eq;
+
+    fn server(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], port))
+    }
+
+    fn table(clock: Arc<dyn Clock>, probe_chance: f64) -> RttTable {
+        RttTable {
+            probe_chance,
+            ..RttTable::with_clock(clock)
+        }
+    }
+
+    #[test]
+    fn first_sample_sets_srtt() {
+        let clock = Arc::new(MockClock::new());
+        let mut rtt = RttTable::with_clock(clock);
+        rtt.record(server(1), Duration::from_millis(100));
+        assert_eq!(rtt.srtt(server(1)), Some(Duration::from_millis(100)));
+        assert_eq!(rtt.srtt(server(2)), None);
+    }
+
+    #[test]
+    fn samples_are_smoothed() {
+        let clock = Arc::new(MockClock::new());
+        let mut rtt = RttTable::with_clock(clock);
+        rtt.record(server(1), Duration::from_millis(100));
+        rtt.record(server(1), Duration::from_millis(200));
+        assert_eq!(rtt.srtt(server(1)), Some(Duration::from_millis(130)));
+    }
+
+    #[test]
+    fn timeouts_are_penalized() {
+        let clock = Arc::new(MockClock::new());
+        let mut rtt = RttTable::with_clock(clock);
+        rtt.record(server(1), Duration::from_millis(100));
+        rtt.record_timeout(server(1), Duration::from_millis(800));
+        assert_eq!(rtt.srtt(server(1)), Some(Duration::from_millis(800)));
+        rtt.record_timeout(server(1), Duration::from_millis(800));
+        assert_eq!(rtt.srtt(server(1)), Some(Duration::from_millis(1600)));
+    }
+
+    #[test]
+    fn srtt_decays_while_unused() {
+        let clock = Arc::new(MockClock::new());
+        let mut rtt = RttTable::with_clock(clock.clone());
+        rtt.record(server(1), Duration::from_millis(800));
+        clock.advance(DECAY_HALF_LIFE);
+        assert_eq!(rtt.srtt(server(1)), Some(Duration::from_millis(400)));
+    }
+
+    #[test]
+    fn order_fastest_first() {
+        let clock = Arc::new(MockClock::new());
+        let mut rtt = table(clock, 0.0);
+        rtt.record(server(1), Duration::from_millis(300));
+        rtt.record(server(2), Duration::from_millis(20));
+        rtt.record_timeout(server(3), Duration::from_millis(800));
+        let ordered = rtt.order(&[server(1), server(2), server(3), server(4)]);
+        assert_eq!(ordered, vec![server(4), server(2), server(1), server(3)]);
+    }
+
+    #[test]
+    fn order_probes_slower_server() {
+        let clock = Arc::new(MockClock::new());
+        let mut rtt = table(clock, 1.0);
+        rtt.record(server(1), Duration::from_millis(20));
+        rtt.record(server(2), Duration::from_millis(300));
+        let ordered = rtt.order(&[server(1), server(2)]);
+        assert_eq!(ordered, vec![server(2), server(1)]);
+    }
+}
@@ -0,0 +1,671 @@
+use crate::acl::{Acl, AclAction};
+use crate::error::DnsError;
+use crate::packet::{tcp_message, DnsPacket};
+use crate::rate_limit::{RateLimiter, RateLimits, ResponseAction};
+use crate::resolver::Resolver;
+use crate::util::lock;
+use log::{debug, error, warn};
+use socket2::{Domain, Socket, Type};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Per RFC 1035 the max size for UDP messages is 512 bytes
+const MAX_UDP_SIZE: usize = 512;
+const DEFAULT_MAX_IN_FLIGHT: usize = 32;
+/// Queries waiting for a free worker, per worker, before new ones are dropped
+const QUEUE_PER_WORKER: usize = 4;
+const DEFAULT_TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_TCP_CONNECTIONS: usize = 64;
+const TCP_BACKLOG: i32 = 128;
+/// How long to try connecting to a TCP listener to wake it for shutdown
+const WAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// An address to answer queries on, and how
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listener {
+    pub addr: SocketAddr,
+    pub udp: bool,
+    pub tcp: bool,
+    /// For IPv6 addresses, only accept IPv6 clients. Otherwise an unspecified address like `[::]`
+    /// also accepts IPv4 clients, as IPv4-mapped addresses.
+    pub v6_only: bool,
+    /// How long a TCP connection may sit without sending a query before it's closed
+    pub tcp_idle_timeout: Duration,
+    pub max_tcp_connections: usize,
+}
+
+impl Listener {
+    /// Listen on `addr` over both UDP and TCP
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            udp: true,
+            tcp: true,
+            v6_only: false,
+            tcp_idle_timeout: DEFAULT_TCP_IDLE_TIMEOUT,
+            max_tcp_connections: DEFAULT_MAX_TCP_CONNECTIONS,
+        }
+    }
+}
+
+/// A listener with its sockets bound
+struct Bound {
+    listener: Listener,
+    udp: Option<UdpSocket>,
+    tcp: Option<TcpListener>,
+}
+
+/// Sockets bound for a set of listeners, ready to be served
+pub struct Listeners(Vec<Bound>);
+
+fn bind_socket(addr: SocketAddr, socket_type: Type, v6_only: bool) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), socket_type, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    if socket_type == Type::STREAM {
+        // So a restarted server can bind while old connections are in TIME_WAIT
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+impl Listeners {
+    /// Bind the sockets for each of `listeners`. When a listener's port is 0, TCP uses the port
+    /// picked for UDP.
+    pub fn bind(listeners: &[Listener]) -> io::Result<Self> {
+        let mut bound = vec![];
+        for listener in listeners {
+            if !listener.udp && !listener.tcp {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Listener {} has neither UDP nor TCP enabled", listener.addr),
+                ));
+            }
+            let mut addr = listener.addr;
+            let udp = match listener.udp {
+                true => {
+                    let socket: UdpSocket =
+                        bind_socket(addr, Type::DGRAM, listener.v6_only)?.into();
+                    addr = socket.local_addr()?;
+                    Some(socket)
+                }
+                false => None,
+            };
+            let tcp = match listener.tcp {
+                true => {
+                    let socket = bind_socket(addr, Type::STREAM, listener.v6_only)?;
+                    socket.listen(TCP_BACKLOG)?;
+                    Some(socket.into())
+                }
+                false => None,
+            };
+            bound.push(Bound {
+                listener: listener.clone(),
+                udp,
+                tcp,
+            });
+        }
+        Ok(Self(bound))
+    }
+
+    /// The address each listener is bound to, with any port 0 replaced by the one picked
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.0
+            .iter()
+            .map(|bound| match (&bound.udp, &bound.tcp) {
+                (Some(udp), _) => udp.local_addr(),
+                (None, Some(tcp)) => tcp.local_addr(),
+                (None, None) => Ok(bound.listener.addr),
+            })
+            .collect()
+    }
+}
+
+/// Where to send the response to a query: back over the socket or connection it arrived on
+enum Reply {
+    Udp(Arc<UdpSocket>),
+    Tcp(Arc<Mutex<TcpStream>>),
+}
+
+/// A query waiting to be resolved
+struct Request {
+    bytes: Vec<u8>,
+    src_addr: SocketAddr,
+    action: AclAction,
+    reply: Reply,
+}
+
+/// Keeps a TCP connection in its listener's open connections until dropped
+struct Connection {
+    id: u64,
+    open: Arc<Mutex<HashMap<u64, TcpStream>>>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        lock(&self.open).remove(&self.id);
+    }
+}
+
+/// How queries are answered, replaced as a whole when the server is reloaded
+#[derive(Clone)]
+struct State {
+    resolver: Arc<Resolver>,
+    acl: Arc<Acl>,
+    rate_limiter: Arc<RateLimiter>,
+}
+
+/// A socket a listener thread blocks on, which it can be woken from when stopping
+#[derive(Clone, Copy)]
+enum Waiting {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+}
+
+impl Waiting {
+    /// Send an empty datagram or open a connection so the listener's recv or accept returns
+    fn wake(self) {
+        let result = match self {
+            Waiting::Udp(addr) => {
+                let addr = reachable(addr);
+                let local: SocketAddr = match addr {
+                    SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                };
+                UdpSocket::bind(local).and_then(|socket| socket.send_to(&[], addr).map(drop))
+            }
+            Waiting::Tcp(addr) => {
+                TcpStream::connect_timeout(&reachable(addr), WAKE_TIMEOUT).map(drop)
+            }
+        };
+        if let Err(err) = result {
+            warn!("Failed to wake listener with error: {:?}", err);
+        }
+    }
+}
+
+/// The loopback address in place of an unspecified one, which can be bound but not sent to
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
+
+/// What a server shares with its threads and handles
+struct Shared {
+    state: Mutex<Arc<State>>,
+    stopping: AtomicBool,
+    waiting: Mutex<Vec<Waiting>>,
+}
+
+impl Shared {
+    fn state(&self) -> Arc<State> {
+        lock(&self.state).clone()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut State)) {
+        let mut state = lock(&self.state);
+        let mut updated = State::clone(&state);
+        f(&mut updated);
+        *state = Arc::new(updated);
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    fn stop(&self) {
+        if !self.stopping.swap(true, Ordering::SeqCst) {
+            for waiting in lock(&self.waiting).iter() {
+                waiting.wake();
+            }
+        }
+    }
+
+    /// Record a socket a listener is about to block on, waking it straight away if the server
+    /// is already stopping
+    fn wait_on(&self, waiting: Waiting) {
+        let mut all = lock(&self.waiting);
+        all.push(waiting);
+        if self.is_stopping() {
+            waiting.wake();
+        }
+    }
+}
+
+/// Controls a running server from another thread, such as a signal handler
+#[derive(Clone)]
+pub struct ServerHandle(Arc<Shared>);
+
+impl ServerHandle {
+    /// Stop receiving queries, finish answering those already received, then return from
+    /// `serve`
+    pub fn shutdown(&self) {
+        self.0.stop();
+    }
+
+    /// Answer queries received from now on with a new resolver, ACL and rate limits, without
+    /// closing any sockets. Queries already received finish with the old ones. Clients keep
+    /// their rate limit state, so a reload doesn't give them a fresh burst.
+    pub fn reload(&self, resolver: Arc<Resolver>, acl: Acl, rate_limits: RateLimits) {
+        self.0.state().rate_limiter.set_limits(rate_limits);
+        self.0.update(|state| {
+            state.resolver = resolver;
+            state.acl = Arc::new(acl);
+        });
+    }
+
+    /// The resolver queries are currently answered from
+    pub fn resolver(&self) -> Arc<Resolver> {
+        self.0.state().resolver.clone()
+    }
+}
+
+/// Answers DNS queries received over UDP and TCP, resolving up to `max_in_flight` of them at once
+/// so a slow recursion doesn't hold up other clients
+pub struct Server {
+    shared: Arc<Shared>,
+    max_in_flight: usize,
+}
+
+impl Server {
+    pub fn new(resolver: Arc<Resolver>) -> Self {
+        let state = State {
+            resolver,
+            acl: Arc::new(Acl::default()),
+            rate_limiter: Arc::new(RateLimiter::new(RateLimits::default())),
+        };
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(Arc::new(state)),
+                stopping: AtomicBool::new(false),
+                waiting: Mutex::new(vec![]),
+            }),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+        }
+    }
+
+    /// Which clients are answered. By default only loopback and private ranges are.
+    pub fn with_acl(self, acl: Acl) -> Self {
+        self.shared.update(|state| state.acl = Arc::new(acl));
+        self
+    }
+
+    /// Limit how fast each client may query, and how many identical responses are sent to each
+    /// client network over UDP. Unlimited by default.
+    pub fn with_rate_limits(self, rate_limits: RateLimits) -> Self {
+        self.shared
+            .update(|state| state.rate_limiter = Arc::new(RateLimiter::new(rate_limits)));
+        self
+    }
+
+    /// Limit the number of queries resolved concurrently, which is also the number of worker
+    /// threads. Queries beyond what the workers can queue are dropped, and clients retry them.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    /// A handle to shut down or reload the server while it's serving
+    pub fn control(&self) -> ServerHandle {
+        ServerHandle(self.shared.clone())
+    }
+
+    /// Receive queries on `socket` and answer them from the worker pool, until shut down
+    pub fn run(&self, socket: UdpSocket) -> io::Result<()> {
+        let listener = Listener {
+            tcp: false,
+            ..Listener::new(socket.local_addr()?)
+        };
+        self.serve(Listeners(vec![Bound {
+            listener,
+            udp: Some(socket),
+            tcp: None,
+        }]))
+    }
+
+    /// Answer queries arriving on all of `listeners` from one worker pool, until one of them
+    /// fails or the server is shut down. Queries already received are answered before returning.
+    pub fn serve(&self, listeners: Listeners) -> io::Result<()> {
+        let (sender, workers) = self.start_workers()?;
+        let (done_sender, done) = mpsc::channel();
+        let mut threads = vec![];
+        for bound in listeners.0 {
+            if let Some(socket) = bound.udp {
+                let addr = socket.local_addr()?;
+                debug!("Server listening on {} over UDP", addr);
+                self.shared.wait_on(Waiting::Udp(addr));
+                let sender = sender.clone();
+                let shared = self.shared.clone();
+                let done_sender = done_sender.clone();
+                threads.push(thread::Builder::new().name(format!("udp-{}", addr)).spawn(
+                    move || done_sender.send(Self::receive_udp(socket, &shared, &sender)),
+                )?);
+            }
+            if let Some(tcp) = bound.tcp {
+                let addr = tcp.local_addr()?;
+                debug!("Server listening on {} over TCP", addr);
+                self.shared.wait_on(Waiting::Tcp(addr));
+                let sender = sender.clone();
+                let shared = self.shared.clone();
+                let done_sender = done_sender.clone();
+                let listener = bound.listener;
+                threads.push(thread::Builder::new().name(format!("tcp-{}", addr)).spawn(
+                    move || done_sender.send(Self::accept_tcp(&tcp, &listener, &shared, &sender)),
+                )?);
+            }
+        }
+        drop(sender);
+        drop(done_sender);
+        let result = done.recv().unwrap_or(Ok(()));
+        // Stop the other listeners too, then let the workers drain the queue
+        self.shared.stop();
+        for thread in threads {
+            let _ = thread.join();
+        }
+        for worker in workers {
+            let _ = worker.join();
+        }
+        debug!("Server stopped");
+        result
+    }
+
+    fn start_workers(&self) -> io::Result<(SyncSender<Request>, Vec<JoinHandle<()>>)> {
+        let (sender, receiver) = mpsc::sync_channel(self.max_in_flight * QUEUE_PER_WORKER);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut workers = vec![];
+        for id in 0..self.max_in_flight {
+            let shared = self.shared.clone();
+            let receiver = receiver.clone();
+            workers.push(
+                thread::Builder::new()
+                    .name(format!("worker-{}", id))
+                    .spawn(move || Self::work(&shared, &receiver))?,
+            );
+        }
+        Ok((sender, workers))
+    }
+
+    fn receive_udp(
+        socket: UdpSocket,
+        shared: &Shared,
+        sender: &SyncSender<Request>,
+    ) -> io::Result<()> {
+        let socket = Arc::new(socket);
+        loop {
+            let mut buf = [0u8; MAX_UDP_SIZE];
+            let received = socket.recv_from(&mut buf);
+            if shared.is_stopping() {
+                return Ok(());
+            }
+            let (n_bytes, src_addr) = match received {
+                Ok((n_bytes, src_addr)) => (n_bytes, src_addr),
+                Err(_) => {
+                    error!("Failed to receive request from socket");
+                    continue;
+                }
+            };
+            debug!("Received request from {:?}", src_addr);
+            let state = shared.state();
+            let action = state.acl.action_for(src_addr.ip());
+            if action == AclAction::Deny {
+                debug!("Dropping request from denied client {:?}", src_addr);
+                continue;
+            }
+            if !state.rate_limiter.allow_query(src_addr.ip()) {
+                debug!("Dropping request from rate limited client {:?}", src_addr);
+                continue;
+            }
+            let request = Request {
+                bytes: buf[..n_bytes].to_vec(),
+                src_addr,
+                action,
+                reply: Reply::Udp(socket.clone()),
+            };
+            match sender.try_send(request) {
+                Ok(()) => {}
+                Err(TrySendError::Full(request)) => warn!(
+                    "Too many queries in flight. Dropping request from {:?}.",
+                    request.src_addr
+                ),
+                Err(TrySendError::Disconnected(_)) => {
+                    return Err(io::Error::other("All server workers have exited"))
+                }
+            }
+        }
+    }
+
+    fn accept_tcp(
+        tcp: &TcpListener,
+        listener: &Listener,
+        shared: &Arc<Shared>,
+        sender: &SyncSender<Request>,
+    ) -> io::Result<()> {
+        let open = Arc::new(Mutex::new(HashMap::new()));
+        let mut next_id = 0u64;
+        for stream in tcp.incoming() {
+            if shared.is_stopping() {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("Failed to accept TCP connection with error: {:?}", err);
+                    continue;
+                }
+            };
+            let action = match stream.peer_addr() {
+                Ok(peer_addr) => shared.state().acl.action_for(peer_addr.ip()),
+                Err(_) => continue,
+            };
+            if action == AclAction::Deny {
+                debug!(
+                    "Closing connection from denied client {:?}",
+                    stream.peer_addr()
+                );
+                continue;
+            }
+            if lock(&open).len() >= listener.max_tcp_connections {
+                warn!(
+                    "Too many TCP connections on {}. Closing connection from {:?}.",
+                    listener.addr,
+                    stream.peer_addr()
+                );
+                continue;
+            }
+            let Ok(handle) = stream.try_clone() else {
+                continue;
+            };
+            lock(&open).insert(next_id, handle);
+            let connection = Connection {
+                id: next_id,
+                open: open.clone(),
+            };
+            next_id += 1;
+            let idle_timeout = listener.tcp_idle_timeout;
+            let shared = shared.clone();
+            let sender = sender.clone();
+            thread::spawn(move || {
+                let _connection = connection;
+                let result = Self::receive_tcp(stream, idle_timeout, &shared, &sender);
+                if let Err(err) = result {
+                    debug!("Closed TCP connection with error: {:?}", err);
+                }
+            });
+        }
+        // Stop reading from open connections, so each closes once its queries are answered
+        for stream in lock(&open).values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        Ok(())
+    }
+
+    /// Read length-prefixed queries from a TCP connection until the client closes it, stays
+    /// idle for `idle_timeout` or is denied by the ACL. Unlike UDP, a full queue makes the client
+    /// wait rather than dropping its query.
+    fn receive_tcp(
+        mut stream: TcpStream,
+        idle_timeout: Duration,
+        shared: &Shared,
+        sender: &SyncSender<Request>,
+    ) -> io::Result<()> {
+        stream.set_read_timeout(Some(idle_timeout))?;
+        stream.set_write_timeout(Some(idle_timeout))?;
+        let src_addr = stream.peer_addr()?;
+        debug!("Accepted TCP connection from {:?}", src_addr);
+        let writer = Arc::new(Mutex::new(stream.try_clone()?));
+        loop {
+            let mut length = [0u8; 2];
+            match stream.read_exact(&mut length) {
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                result => result?,
+            }
+            let mut bytes = vec![0u8; u16::from_be_bytes(length) as usize];
+            stream.read_exact(&mut bytes)?;
+            debug!("Received request from {:?}", src_addr);
+            // Checked for each query, so a reloaded ACL applies to open connections
+            let state = shared.state();
+            let action = state.acl.action_for(src_addr.ip());
+            if action == AclAction::Deny {
+                debug!("Closing connection from denied client {:?}", src_addr);
+                return Ok(());
+            }
+            if !state.rate_limiter.allow_query(src_addr.ip()) {
+                // Refuse rather than stay silent, so the client isn't left waiting on the stream
+                let Ok(query) = DnsPacket::from_bytes(&bytes) else {
+                    debug!("Closing connection from rate limited client {:?}", src_addr);
+                    return Ok(());
+                };
+                debug!("Refusing request from rate limited client {:?}", src_addr);
+                let reply = Reply::Tcp(writer.clone());
+                Self::send_response(query.make_refused_response(), &src_addr, &reply);
+                continue;
+            }
+            let request = Request {
+                bytes,
+                src_addr,
+                action,
+                reply: Reply::Tcp(writer.clone()),
+            };
+            if sender.send(request).is_err() {
+                return Err(io::Error::other("All server workers have exited"));
+            }
+        }
+    }
+
+    fn work(shared: &Shared, receiver: &Mutex<Receiver<Request>>) {
+        loop {
+            // Only hold the lock while waiting, so other workers can take the next request
+            let request = match lock(receiver).recv() {
+                Ok(request) => request,
+                Err(_) => return,
+            };
+            let state = shared.state();
+            let Some(mut response) = Self::respond(&state.resolver, &request) else {
+                continue;
+            };
+            if let Reply::Udp(_) = request.reply {
+                match state
+                    .rate_limiter
+                    .response_action(request.src_addr.ip(), &response)
+                {
+                    ResponseAction::Send => {}
+                    ResponseAction::Truncate => response = response.truncate(),
+                    ResponseAction::Drop => {
+                        debug!("Rate limited response to {:?}", request.src_addr);
+                        continue;
+                    }
+                }
+            }
+            Self::send_response(response, &request.src_addr, &request.reply);
+        }
+    }
+
+    /// Answer a query as the client's ACL action allows. Denied clients never reach here.
+    fn respond(resolver: &Resolver, request: &Request) -> Option<DnsPacket> {
+        match request.action {
+            AclAction::Allow => Self::handle(resolver, &request.bytes),
+            AclAction::Deny => None,
+            AclAction::Refuse | AclAction::AllowLocalOnly => {
+                let query_packet = DnsPacket::from_bytes(&request.bytes).ok()?;
+                if request.action == AclAction::AllowLocalOnly {
+                    if let Some(response) = resolver.resolve_local(&query_packet) {
+                        return Some(response);
+                    }
+                }
+                debug!("Refusing request from {:?}", request.src_addr);
+                Some(query_packet.make_refused_response())
+            }
+        }
+    }
+
+    /// Resolve one query, returning the response to send or None when the query can't be decoded
+    pub fn handle(resolver: &Resolver, bytes: &[u8]) -> Option<DnsPacket> {
+        match DnsPacket::from_bytes(bytes) {
+            Ok(query_packet) => match resolver.resolve_packet(query_packet.clone()) {
+                Ok(response_packet) => Some(response_packet),
+                Err(err) => {
+                    error!(
+                        "Resolver failed with error {:?}. Sending error response.",
+                        err
+                    );
+                    Some(query_packet.make_error_response(err))
+                }
+            },
+            Err(err) => {
+                error!(
+                    "Failed to decode request packet with error {:?}. Skipping.",
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    fn send_response(packet: DnsPacket, src_addr: &SocketAddr, reply: &Reply) {
+        debug!("Sending response to {:?}", src_addr);
+        let encoded = match reply {
+            Reply::Udp(_) => Self::encode_for_udp(packet),
+            Reply::Tcp(_) => packet.to_bytes().and_then(|bytes| tcp_message(&bytes)),
+        };
+        let bytes = match encoded {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                error!(
+                    "Failed to encode the response with error: {:?}. Skipping.",
+                    err
+                );
+                return;
+            }
+        };
+        let result = match reply {
+            Reply::Udp(socket) => socket.send_to(&bytes, src_addr).map(|_| ()),
+            Reply::Tcp(stream) => lock(stream).write_all(&bytes),
+        };
+        if let Err(err) = result {
+            error!("Failed to send response with error: {:?}. Skipping.", err)
+        }
+    }
+
+    /// Encode a response for a datagram, truncating it when it's too big so the client retries
+    /// over TCP
+    fn encode_for_udp(packet: DnsPacket) -> Result<Vec<u8>, DnsError> {
+        let bytes = packet.clone().to_bytes()?;
+        if bytes.len() <= MAX_UDP_SIZE {
+            return Ok(bytes);
+        }
+        packet.truncate().to_bytes()
+    }
+}
@@ -0,0 +1,206 @@
+use crate::error::DnsError;
+use crate::packet::DnsPacket;
+use crate::question::DnsQuestion;
+use crate::record::DnsRecord;
+use crate::rr_fields::HeaderFlags;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// What the resolver did while resolving a name, step by step, like `dig +trace`
+#[derive(Debug)]
+pub struct Trace {
+    pub steps: Vec<TraceStep>,
+    pub result: Result<DnsPacket, DnsError>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceStep {
+    /// Time since resolution started
+    pub elapsed: Duration,
+    /// How deeply the step is nested in lookups of nameserver addresses
+    pub depth: usize,
+    pub event: TraceEvent,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+    /// The name is on the blocklist
+    Blocked { name: String },
+    /// The answer came from the cache without querying anyone
+    CacheHit { record: DnsRecord },
+    /// A query sent upstream, with how long the server took to respond or fail
+    Query {
+        server: SocketAddr,
+        question: DnsQuestion,
+        rtt: Duration,
+        outcome: Result<ResponseSummary, DnsError>,
+    },
+    /// A referral to the nameservers of a zone closer to the name
+    Referral {
+        zone: String,
+        nameservers: Vec<String>,
+    },
+    /// Resolution restarted at the target of a CNAME
+    Cname { name: String, target: String },
+}
+
+/// The parts of an upstream response which matter for following resolution
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseSummary {
+    pub rcode: u16,
+    pub answers: Vec<DnsRecord>,
+    pub authorities: usize,
+    pub additionals: usize,
+}
+
+impl ResponseSummary {
+    pub fn new(response: &DnsPacket) -> Self {
+        Self {
+            rcode: response.header.rcode(),
+            answers: response.answers.clone(),
+            authorities: response.authorities.len(),
+            additionals: response.additionals.len(),
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn rcode_name(rcode: u16) -> String {
+    let name = match rcode {
+        0 => "NOERROR",
+        x if x == HeaderFlags::RCODE_FORMAT_ERR as u16 => "FORMERR",
+        x if x == HeaderFlags::RCODE_SERVER_ERR as u16 => "SERVFAIL",
+        x if x == HeaderFlags::RCODE_NAME_ERR as u16 => "NXDOMAIN",
+        x if x == HeaderFlags::RCODE_NOT_IMPL as u16 => "NOTIMP",
+        x if x == HeaderFlags::RCODE_REFUSED as u16 => "REFUSED",
+        _ => return format!("RCODE{}", rcode),
+    };
+    name.to_string()
+}
+
+impl fmt::Display for TraceStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let indent = "  ".repeat(self.depth);
+        write!(f, "[{:>7.1}ms] {}", millis(self.elapsed), indent)?;
+        match &self.event {
+            TraceEvent::Blocked { name } => write!(f, "{} is blocklisted", name),
+            TraceEvent::CacheHit { record } => write!(f, "cache: {}", record),
+            TraceEvent::Query {
+                server,
+                question,
+                rtt,
+                outcome,
+            } => {
+                write!(
+                    f,
+                    "{} {:?} @{} ({:.1}ms): ",
+                    question.name,
+                    question.qtype,
+                    server,
+                    millis(*rtt)
+                )?;
+                match outcome {
+                    Ok(summary) => {
+                        write!(
+                            f,
+                            "{}, {} answers, {} authority, {} additional",
+                            rcode_name(summary.rcode),
+                            summary.answers.len(),
+                            summary.authorities,
+                            summary.additionals
+                        )?;
+                        for answer in &summary.answers {
+                            write!(f, "\n{:12}{}  {}", "", indent, answer)?;
+                        }
+                        Ok(())
+                    }
+                    Err(err) => write!(f, "{:?}", err),
+                }
+            }
+            TraceEvent::Referral { zone, nameservers } => {
+                let zone = if zone.is_empty() { "." } else { zone };
+                write!(f, "referral to {} via {}", zone, nameservers.join(", "))
+            }
+            TraceEvent::Cname { name, target } => write!(f, "{} is an alias for {}", name, target),
+        }
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for step in &self.steps {
+            writeln!(f, "{}", step)?;
+        }
+        match &self.result {
+            Ok(response) => {
+                write!(f, "result: {}", rcode_name(response.header.rcode()))?;
+                for answer in &response.answers {
+                    write!(f, "\n  {}", answer)?;
+                }
+                Ok(())
+            }
+            Err(err) => write!(f, "result: {:?}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::Rdata;
+    use crate::rr_fields::{Class, Type};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn display_query_step() {
+        let step = TraceStep {
+            elapsed: Duration::from_millis(12),
+            depth: 1,
+            event: TraceEvent::Query {
+                server: "192.0.2.53:53".parse().unwrap(),
+                question: DnsQuestion::new("example.com", Type::A, Class::CLASS_IN),
+                rtt: Duration::from_millis(5),
+                outcome: Ok(ResponseSummary {
+                    rcode: 0,
+                    answers: vec![DnsRecord {
+                        name: "example.com".to_string(),
+                        class: Class::CLASS_IN,
+                        ttl: 60,
+                        rdata: Rdata::A("192.0.2.1".to_string()),
+                    }],
+                    authorities: 0,
+                    additionals: 0,
+                }),
+            },
+        };
+        let expected = "[   12.0ms]   example.com A @192.0.2.53:53 (5.0ms): NOERROR, 1 answers, \
+                        0 authority, 0 additional\n                example.com 60 IN A 192.0.2.1";
+        assert_eq!(step.to_string(), expected);
+    }
+
+    #[test]
+    fn display_referral_to_root() {
+        let step = TraceStep {
+            elapsed: Duration::ZERO,
+            depth: 0,
+            event: TraceEvent::Referral {
+                zone: String::new(),
+                nameservers: vec!["a.root-servers.net".to_string()],
+            },
+        };
+        assert_eq!(
+            step.to_string(),
+            "[    0.0ms] referral to . via a.root-servers.net"
+        );
+    }
+
+    #[test]
+    fn rcode_names() {
+        assert_eq!(rcode_name(3), "NXDOMAIN");
+        assert_eq!(rcode_name(9), "RCODE9");
+    }
+}
@@ -0,0 +1,292 @@
+use crate::error::DnsError;
+use crate::packet::{DnsPacket, OutgoingQuery, QueryOptions};
+use crate::question::DnsQuestion;
+use crate::util::lock;
+use log::debug;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Mutex;
+#[cfg(feature = "tokio")]
+use {
+    crate::packet::{recv_error, tcp_message},
+    log::warn,
+    tokio::io::{AsyncReadExt, AsyncWriteExt},
+    tokio::net::{TcpStream, UdpSocket},
+    tokio::time::{timeout, timeout_at, Instant},
+};
+
+pub type QueryFuture<'a> = Pin<Box<dyn Future<Output = Result<DnsPacket, DnsError>> + Send + 'a>>;
+
+/// How the resolver sends queries to nameservers, so tests can swap the network for a
+/// `MockTransport`. Implementations must only return responses which answer the question.
+pub trait Transport: Send + Sync {
+    /// Send `question` to `nameserver` and wait for a response which answers it
+    fn query<'a>(
+        &'a self,
+        nameserver: SocketAddr,
+        question: &'a DnsQuestion,
+        options: QueryOptions,
+    ) -> QueryFuture<'a>;
+}
+
+/// Blocking UDP, returning truncated responses as they are
+pub struct UdpTransport;
+
+impl Transport for UdpTransport {
+    fn query<'a>(
+        &'a self,
+        nameserver: SocketAddr,
+        question: &'a DnsQuestion,
+        options: QueryOptions,
+    ) -> QueryFuture<'a> {
+        Box::pin(async move { DnsPacket::send_query(nameserver, question, options) })
+    }
+}
+
+/// Blocking TCP
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn query<'a>(
+        &'a self,
+        nameserver: SocketAddr,
+        question: &'a DnsQuestion,
+        options: QueryOptions,
+    ) -> QueryFuture<'a> {
+        Box::pin(async move { DnsPacket::send_query_tcp(nameserver, question, options) })
+    }
+}
+
+/// Blocking sockets, for the synchronous API. Each query goes over UDP first and is repeated
+/// over TCP when the response is truncated.
+pub struct BlockingTransport;
+
+impl Transport for BlockingTransport {
+    fn query<'a>(
+        &'a self,
+        nameserver: SocketAddr,
+        question: &'a DnsQuestion,
+        options: QueryOptions,
+    ) -> QueryFuture<'a> {
+        Box::pin(async move {
+            let response = UdpTransport.query(nameserver, question, options).await?;
+            if !response.is_truncated() {
+                return Ok(response);
+            }
+            debug!("Response from {} truncated, retrying over TCP", nameserver);
+            TcpTransport.query(nameserver, question, options).await
+        })
+    }
+}
+
+type Respond = Box<dyn Fn(DnsPacket) -> Option<DnsPacket> + Send + Sync>;
+
+/// Nameservers in memory, for testing resolution offline. Queries and responses are encoded
+/// and checked as if they went over the network, and servers which don't respond, or aren't
+/// known, time out straight away.
+#[derive(Default)]
+pub struct MockTransport {
+    servers: HashMap<SocketAddr, Respond>,
+    queries: Mutex<Vec<(SocketAddr, DnsQuestion)>>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer queries sent to `addr` with the response built by `respond`, or none if it
+    /// returns None
+    pub fn with_server(
+        mut self,
+        addr: SocketAddr,
+        respond: impl Fn(DnsPacket) -> Option<DnsPacket> + Send + Sync + 'static,
+    ) -> Self {
+        self.servers.insert(addr, Box::new(respond));
+        self
+    }
+
+    /// Every query sent so far, in order, with the nameserver it was sent to
+    pub fn queries(&self) -> Vec<(SocketAddr, DnsQuestion)> {
+        lock(&self.queries).clone()
+    }
+
+    fn exchange(
+        &self,
+        nameserver: SocketAddr,
+        question: &DnsQuestion,
+        options: QueryOptions,
+    ) -> Result<DnsPacket, DnsError> {
+        lock(&self.queries).push((nameserver, question.clone()));
+        let timed_out = DnsError::TimeoutError("Timed out waiting for response");
+        let respond = self.servers.get(&nameserver).ok_or(timed_out.clone())?;
+        let query = OutgoingQuery::new(question, &options)?;
+        let response = respond(DnsPacket::from_bytes(&query.bytes)?).ok_or(timed_out.clone())?;
+        query.accept(&response.to_bytes()?).ok_or(timed_out)
+    }
+}
+
+impl Transport for MockTransport {
+    fn query<'a>(
+        &'a self,
+        nameserver: SocketAddr,
+        question: &'a DnsQuestion,
+        options: QueryOptions,
+    ) -> QueryFuture<'a> {
+        Box::pin(async move { self.exchange(nameserver, question, options) })
+    }
+}
+
+/// Non-blocking sockets on the tokio runtime driving the query
+#[cfg(feature = "tokio")]
+pub struct TokioTransport;
+
+#[cfg(feature = "tokio")]
+impl TokioTransport {
+    async fn send_query(
+        nameserver: SocketAddr,
+        question: &DnsQuestion,
+        options: QueryOptions,
+    ) -> Result<DnsPacket, DnsError> {
+        let mut buf: [u8; 1024] = [0; 1024];
+        let query = OutgoingQuery::new(question, &options)?;
+        let socket = DnsPacket::bind_random_port(nameserver)?;
+        let socket = socket
+            .set_nonblocking(true)
+            .and_then(|_| UdpSocket::from_std(socket))
+            .map_err(|_| DnsError::NetworkError("Failed binding to socket"))?;
+        socket
+            .send_to(&query.bytes, nameserver)
+            .await
+            .map_err(|_| DnsError::NetworkError("Failed sending query"))?;
+        // Keep waiting through datagrams which don't answer this query, as they may be spoofed
+        let deadline = Instant::now() + options.timeout;
+        loop {
+            let (num_bytes, src_addr) = timeout_at(deadline, socket.recv_from(&mut buf))
+                .await
+                .map_err(|_| DnsError::TimeoutError("Timed out waiting for response"))?
+                .map_err(recv_error)?;
+            if src_addr != nameserver {
+                warn!("Discarding response from unexpected address {}", src_addr);
+                continue;
+            }
+            if let Some(response) = query.accept(&buf[..num_bytes]) {
+                return Ok(response);
+            }
+        }
+    }
+
+    async fn send_query_tcp(
+        nameserver: SocketAddr,
+        question: &DnsQuestion,
+        options: QueryOptions,
+    ) -> Result<DnsPacket, DnsError> {
+        let query = OutgoingQuery::new(question, &options)?;
+        let exchange = async {
+            let mut stream = TcpStream::connect(nameserver)
+                .await
+                .map_err(|_| DnsError::NetworkError("Failed connecting to nameserver"))?;
+            stream
+                .write_all(&tcp_message(&query.bytes)?)
+                .await
+                .map_err(|_| DnsError::NetworkError("Failed sending query"))?;
+            let mut length = [0u8; 2];
+            stream.read_exact(&mut length).await.map_err(recv_error)?;
+            let mut buf = vec![0u8; u16::from_be_bytes(length) as usize];
+            stream.read_exact(&mut buf).await.map_err(recv_error)?;
+            query.accept(&buf).ok_or(DnsError::NetworkError(
+                "Nameserver sent a TCP response which doesn't match the query",
+            ))
+        };
+        timeout(options.timeout, exchange)
+            .await
+            .map_err(|_| DnsError::TimeoutError("Timed out waiting for response"))?
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Transport for TokioTransport {
+    fn query<'a>(
+        &'a self,
+        nameserver: SocketAddr,
+        question: &'a DnsQuestion,
+        options: QueryOptions,
+    ) -> QueryFuture<'a> {
+        Box::pin(async move {
+            let response = Self::send_query(nameserver, question, options).await?;
+            if !response.is_truncated() {
+                return Ok(response);
+            }
+            debug!("Response from {} truncated, retrying over TCP", nameserver);
+            Self::send_query_tcp(nameserver, question, options).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::{DnsRecord, Rdata};
+    use crate::rr_fields::{Class, HeaderFlags, Type};
+    use crate::util::block_on;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    fn answer(mut packet: DnsPacket) -> Option<DnsPacket> {
+        packet.header.flags |= HeaderFlags::QR_RESPONSE as u16;
+        packet.header.num_answers = 1;
+        packet.answers.push(DnsRecord {
+            name: packet.questions[0].name.clone(),
+            class: Class::CLASS_IN,
+            ttl: 60,
+            rdata: Rdata::A("192.0.2.1".to_string()),
+        });
+        Some(packet)
+    }
+
+    fn options() -> QueryOptions {
+        QueryOptions {
+            recursion_desired: false,
+            randomize_case: true,
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn mock_answers_and_records_queries() {
+        let server: SocketAddr = "192.0.2.53:53".parse().unwrap();
+        let transport = MockTransport::new().with_server(server, answer);
+        let question = DnsQuestion::new("example.com", Type::A, Class::CLASS_IN);
+        let response = block_on(transport.query(server, &question, options())).unwrap();
+        assert_eq!(response.questions[0].name, "example.com");
+        assert_eq!(response.answers[0].rdata, Rdata::A("192.0.2.1".to_string()));
+        assert_eq!(transport.queries(), vec![(server, question)]);
+    }
+
+    #[test]
+    fn mock_silent_and_unknown_servers_time_out() {
+        let silent: SocketAddr = "192.0.2.53:53".parse().unwrap();
+        let unknown: SocketAddr = "192.0.2.54:53".parse().unwrap();
+        let transport = MockTransport::new().with_server(silent, |_| None);
+        let question = DnsQuestion::new("example.com", Type::A, Class::CLASS_IN);
+        for server in [silent, unknown] {
+            let result = block_on(transport.query(server, &question, options()));
+            assert!(matches!(result, Err(DnsError::TimeoutError(_))));
+        }
+    }
+
+    #[test]
+    fn mock_rejects_mismatched_response() {
+        let server: SocketAddr = "192.0.2.53:53".parse().unwrap();
+        let transport = MockTransport::new().with_server(server, |packet| {
+            let mut response = answer(packet)?;
+            response.header.id = response.header.id.wrapping_add(1);
+            Some(response)
+        });
+        let question = DnsQuestion::new("example.com", Type::A, Class::CLASS_IN);
+        let result = block_on(transport.query(server, &question, options()));
+        assert!(matches!(result, Err(DnsError::TimeoutError(_))));
+    }
+}
@@ -1,12 +1,48 @@
 use crate::error::DnsError;
+use rand::rngs::OsRng;
+use rand::Rng;
 use std::collections::HashSet;
+use std::future::Future;
 use std::io::Cursor;
 use std::io::Read;
+use std::pin::pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+/// Lock `mutex`, carrying on with the data if another thread panicked while holding it.
+/// Every update leaves the shared caches consistent, so they stay usable after a panic.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run `future` to completion on the current thread, for the synchronous API. Futures driven
+/// this way must not depend on an async runtime, so they use blocking I/O.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
 
 pub fn encode_dns_name(domain_name: &str) -> Vec<u8> {
     assert!(domain_name.is_ascii());
     let mut encoded: Vec<u8> = vec![];
-    for part in domain_name.split('.') {
+    // The root name is just the terminating zero length
+    for part in domain_name.split('.').filter(|part| !part.is_empty()) {
         assert!(part.len() <= 63);
         let length = part.len() as u8;
         encoded.push(length);
@@ -16,6 +52,47 @@ pub fn encode_dns_name(domain_name: &str) -> Vec<u8> {
     encoded
 }
 
+/// Lowercase a domain name and strip any trailing dot so names can be compared
+pub fn normalize_name(domain_name: &str) -> String {
+    domain_name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Whether `domain_name` is `zone` or below it, comparing whole labels.
+/// Both names must already be normalized. The root zone is the empty string.
+pub fn is_subdomain(domain_name: &str, zone: &str) -> bool {
+    if zone.is_empty() || domain_name == zone {
+        return true;
+    }
+    domain_name
+        .strip_suffix(zone)
+        .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/// Randomly flip the case of each letter in a domain name (DNS 0x20)
+pub fn randomize_case(domain_name: &str) -> String {
+    domain_name
+        .chars()
+        .map(|c| {
+            if OsRng.gen() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+/// Compare domain names the way DNS does, ignoring ASCII case
+pub fn names_equal(name: &str, other: &str) -> bool {
+    name.trim_end_matches('.')
+        .eq_ignore_ascii_case(other.trim_end_matches('.'))
+}
+
+/// Compare domain names preserving case, for checking a DNS 0x20 name was echoed exactly
+pub fn names_equal_case_sensitive(name: &str, other: &str) -> bool {
+    name.trim_end_matches('.') == other.trim_end_matches('.')
+}
+
 pub fn decode_dns_name(reader: &mut Cursor<&[u8]>) -> Result<String, DnsError> {
     inner_decode_dns_name(reader, &mut HashSet::new())
 }
@@ -108,4 +185,37 @@ mod tests {
         let res = decode_dns_name(&mut reader);
         assert_eq!(expected, res)
     }
+    #[test]
+    fn encode_root_name() {
+        assert_eq!(encode_dns_name(""), vec![0]);
+        assert_eq!(encode_dns_name("."), vec![0]);
+    }
+    #[test]
+    fn randomize_case_keeps_name() {
+        let name = "www.example-1.com";
+        let randomized = randomize_case(name);
+        assert!(names_equal(name, &randomized));
+        assert_eq!(randomized.len(), name.len());
+    }
+    #[test]
+    fn compare_names() {
+        assert!(names_equal("WwW.Example.COM", "www.example.com."));
+        assert!(!names_equal("www.example.com", "www.example.net"));
+        assert!(names_equal_case_sensitive(
+            "WwW.Example.COM",
+            "WwW.Example.COM."
+        ));
+        assert!(!names_equal_case_sensitive(
+            "WwW.Example.COM",
+            "www.example.com"
+        ));
+    }
+    #[test]
+    fn subdomain_matches_whole_labels() {
+        assert!(is_subdomain("www.example.com", "example.com"));
+        assert!(is_subdomain("example.com", "example.com"));
+        assert!(is_subdomain("example.com", ""));
+        assert!(!is_subdomain("badexample.com", "example.com"));
+        assert!(!is_subdomain("example.com", "www.example.com"));
+    }
 }
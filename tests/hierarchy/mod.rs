@@ -0,0 +1,212 @@
+//! Authoritative nameservers on loopback serving zones written as records, one per line, so
+//! recursion can be tested end to end without the internet
+
+use dnsvisor::packet::DnsPacket;
+use dnsvisor::record::{DnsRecord, Rdata};
+use dnsvisor::resolver::Resolver;
+use dnsvisor::root_hints::RootHints;
+use dnsvisor::rr_fields::{Class, HeaderFlags, Type};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+
+/// Per RFC 1035 the max size for UDP messages is 512 bytes, beyond which responses are truncated
+const MAX_UDP_SIZE: usize = 512;
+
+/// Servers for a tree of zones, each on its own loopback address and all on the same port, since
+/// referrals can only give nameserver addresses and not ports
+pub struct Hierarchy {
+    port: u16,
+    // Sockets for 127.0.0.1 which reserve the port, used by the server on that address
+    reserved: Option<(UdpSocket, TcpListener)>,
+    roots: Vec<SocketAddr>,
+}
+
+impl Hierarchy {
+    pub fn new() -> Self {
+        loop {
+            let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let port = udp.local_addr().unwrap().port();
+            if let Ok(tcp) = TcpListener::bind(("127.0.0.1", port)) {
+                return Self {
+                    port,
+                    reserved: Some((udp, tcp)),
+                    roots: vec![],
+                };
+            }
+        }
+    }
+
+    /// Serve the zone in `zone_data` over UDP and TCP on the loopback address `ip`. The zone's
+    /// origin is the owner of its SOA record, and `;` starts a comment.
+    pub fn serve(mut self, ip: &str, zone_data: &str) -> Self {
+        let zone = Arc::new(Zone::parse(zone_data));
+        let ip: IpAddr = ip.parse().unwrap();
+        let addr = SocketAddr::new(ip, self.port);
+        let (udp, tcp) = match self.reserved.take() {
+            Some(sockets) if ip == Ipv4Addr::LOCALHOST => sockets,
+            reserved => {
+                self.reserved = reserved;
+                (
+                    UdpSocket::bind(addr).unwrap(),
+                    TcpListener::bind(addr).unwrap(),
+                )
+            }
+        };
+        if zone.origin.is_empty() {
+            self.roots.push(addr);
+        }
+        let udp_zone = zone.clone();
+        thread::spawn(move || serve_udp(&udp, &udp_zone));
+        thread::spawn(move || serve_tcp(&tcp, &zone));
+        self
+    }
+
+    /// A resolver which recurses from the root zone's servers
+    pub fn resolver(&self) -> Resolver {
+        Resolver::default()
+            .with_root_hints(RootHints::from_addrs(self.roots.clone()).unwrap())
+            .with_nameserver_port(self.port)
+    }
+}
+
+/// Respond to `query` with one answer for its question, holding `rdata`
+pub fn answer(query: DnsPacket, rdata: Rdata) -> DnsPacket {
+    let mut response = query;
+    response.header.flags |= HeaderFlags::QR_RESPONSE as u16;
+    response.header.num_answers = 1;
+    response.answers.push(DnsRecord {
+        name: response.questions[0].name.clone(),
+        class: Class::CLASS_IN,
+        ttl: 60,
+        rdata,
+    });
+    response
+}
+
+fn serve_udp(socket: &UdpSocket, zone: &Zone) {
+    loop {
+        let mut buf = [0u8; MAX_UDP_SIZE];
+        let (n_bytes, src_addr) = socket.recv_from(&mut buf).unwrap();
+        let query = DnsPacket::from_bytes(&buf[..n_bytes]).unwrap();
+        let mut response = zone.answer(query.clone()).to_bytes().unwrap();
+        if response.len() > MAX_UDP_SIZE {
+            let mut truncated = query;
+            truncated.header.flags |=
+                HeaderFlags::QR_RESPONSE as u16 | HeaderFlags::TC_TRUNCATED as u16;
+            response = truncated.to_bytes().unwrap();
+        }
+        socket.send_to(&response, src_addr).unwrap();
+    }
+}
+
+fn serve_tcp(listener: &TcpListener, zone: &Zone) {
+    for stream in listener.incoming() {
+        let _ = stream.map(|mut stream| answer_tcp(&mut stream, zone));
+    }
+}
+
+fn answer_tcp(stream: &mut TcpStream, zone: &Zone) -> std::io::Result<()> {
+    let mut length = [0u8; 2];
+    stream.read_exact(&mut length)?;
+    let mut buf = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut buf)?;
+    let query = DnsPacket::from_bytes(&buf).unwrap();
+    let response = zone.answer(query).to_bytes().unwrap();
+    stream.write_all(&(response.len() as u16).to_be_bytes())?;
+    stream.write_all(&response)
+}
+
+fn is_at_or_below(name: &str, zone: &str) -> bool {
+    zone.is_empty() || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+struct Zone {
+    origin: String,
+    records: Vec<DnsRecord>,
+}
+
+impl Zone {
+    fn parse(zone_data: &str) -> Self {
+        let records: Vec<DnsRecord> = zone_data
+            .lines()
+            .map(|line| line.split(';').next().unwrap().trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| line.parse().unwrap())
+            .collect();
+        let origin = records
+            .iter()
+            .find(|record| record.get_type() == Type::SOA)
+            .expect("zone has no SOA record")
+            .name
+            .clone();
+        Self { origin, records }
+    }
+
+    fn records_at<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a DnsRecord> {
+        self.records
+            .iter()
+            .filter(move |record| record.name == name)
+    }
+
+    /// The zone cut at or above `name`, if it has been delegated to other nameservers
+    fn delegation_for(&self, name: &str) -> Option<&str> {
+        self.records
+            .iter()
+            .filter(|record| record.get_type() == Type::NS && record.name != self.origin)
+            .map(|record| record.name.as_str())
+            .filter(|cut| is_at_or_below(name, cut))
+            .min_by_key(|cut| cut.len())
+    }
+
+    /// Answer like an authoritative server: with a referral below a zone cut, a CNAME or the
+    /// records asked for, or the SOA when the name has no such records or doesn't exist
+    fn answer(&self, query: DnsPacket) -> DnsPacket {
+        let mut response = query;
+        response.header.flags |= HeaderFlags::QR_RESPONSE as u16;
+        let question = response.questions[0].clone();
+        let name = question.name.to_ascii_lowercase();
+        let soa = self
+            .records_at(&self.origin)
+            .find(|r| r.get_type() == Type::SOA);
+        if let Some(cut) = self.delegation_for(&name) {
+            let nameservers: Vec<DnsRecord> = self
+                .records_at(cut)
+                .filter(|record| record.get_type() == Type::NS)
+                .cloned()
+                .collect();
+            for nameserver in &nameservers {
+                if let Rdata::NS(ns_name) = &nameserver.rdata {
+                    let glue = self
+                        .records_at(ns_name)
+                        .filter(|record| matches!(record.get_type(), Type::A | Type::AAAA));
+                    response.additionals.extend(glue.cloned());
+                }
+            }
+            response.authorities = nameservers;
+        } else {
+            response.answers = self
+                .records_at(&name)
+                .filter(|record| {
+                    record.get_type() == question.qtype || record.get_type() == Type::CNAME
+                })
+                .cloned()
+                .collect();
+            if response.answers.is_empty() {
+                let exists = self
+                    .records
+                    .iter()
+                    .any(|record| is_at_or_below(&record.name, &name));
+                if !exists {
+                    response.header.flags |= HeaderFlags::RCODE_NAME_ERR as u16;
+                }
+                response.authorities.extend(soa.cloned());
+            }
+        }
+        response.header.num_answers = response.answers.len() as u16;
+        response.header.num_authorities = response.authorities.len() as u16;
+        response.header.num_additionals = response.additionals.len() as u16;
+        response
+    }
+}
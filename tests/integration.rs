@@ -1,20 +1,1023 @@
-use dnsvisor::resolver::Resolver;
-use dnsvisor::rr_fields::Type;
+mod hierarchy;
+
+use dnsvisor::acl::{Acl, AclAction};
+#[cfg(feature = "tokio")]
+use dnsvisor::async_resolver::AsyncResolver;
+use dnsvisor::config::ResolverConfig;
+use dnsvisor::error::DnsError;
+use dnsvisor::local::LocalRecords;
+use dnsvisor::packet::{DnsPacket, QueryOptions};
+use dnsvisor::question::DnsQuestion;
+use dnsvisor::rate_limit::{ClientLimits, RateLimits};
+use dnsvisor::record::{DnsRecord, Rdata};
+use dnsvisor::resolver::{
+    BlockResponse, Blocking, IpPreference, Limits, Resolver, Timeouts, UpstreamMode,
+};
+use dnsvisor::root_hints::RootHints;
+use dnsvisor::rr_fields::{Class, HeaderFlags, Type};
+use dnsvisor::server::{Listener, Listeners, Server};
+use dnsvisor::trace::{TraceEvent, TraceStep};
+use dnsvisor::transport::MockTransport;
+use hierarchy::{answer, Hierarchy};
+use std::collections::HashSet;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ROOT_ZONE: &str = "
+. 86400 IN SOA a.root-servers.test. hostmaster.root-servers.test. 1 1800 900 604800
+test. 172800 IN NS ns.nic.test.
+ns.nic.test. 172800 IN A 127.0.0.2
+net. 172800 IN NS ns.nic.net.
+ns.nic.net. 172800 IN A 127.0.0.3
+";
+
+const TEST_ZONE: &str = "
+test. 86400 IN SOA ns.nic.test. hostmaster.nic.test. 1 1800 900 604800
+example.test. 86400 IN NS ns1.example.test.
+ns1.example.test. 86400 IN A 127.0.0.4
+; served by a nameserver in another TLD, so there's no glue
+glueless.test. 86400 IN NS dns.hosting.net.
+";
+
+const NET_ZONE: &str = "
+net. 86400 IN SOA ns.nic.net. hostmaster.nic.net. 1 1800 900 604800
+hosting.net. 86400 IN NS ns.hosting.net.
+ns.hosting.net. 86400 IN A 127.0.0.5
+";
+
+const HOSTING_ZONE: &str = "
+hosting.net. 3600 IN SOA ns.hosting.net. hostmaster.hosting.net. 1 1800 900 604800
+hosting.net. 3600 IN NS ns.hosting.net.
+ns.hosting.net. 3600 IN A 127.0.0.5
+dns.hosting.net. 3600 IN A 127.0.0.6
+web.hosting.net. 3600 IN A 192.0.2.3
+";
+
+const GLUELESS_ZONE: &str = "
+glueless.test. 3600 IN SOA dns.hosting.net. hostmaster.hosting.net. 1 1800 900 604800
+glueless.test. 3600 IN NS dns.hosting.net.
+www.glueless.test. 3600 IN A 192.0.2.2
+shop.glueless.test. 3600 IN CNAME web.hosting.net.
+";
+
+/// Servers for the root, the `test` and `net` TLDs and zones below them, where `example.test`
+/// has a name with more addresses than fit in a UDP response
+fn start_hierarchy() -> Hierarchy {
+    let big: String = (1..=20)
+        .map(|i| format!("big.example.test. 3600 IN A 192.0.2.{}\n", i))
+        .collect();
+    let example_zone = format!(
+        "example.test. 3600 IN SOA ns1.example.test. hostmaster.example.test. 1 1800 900 604800
+        example.test. 3600 IN NS ns1.example.test.
+        ns1.example.test. 3600 IN A 127.0.0.4
+        www.example.test. 3600 IN A 192.0.2.1
+        alias.example.test. 3600 IN CNAME shop.glueless.test.
+        {}",
+        big
+    );
+    Hierarchy::new()
+        .serve("127.0.0.1", ROOT_ZONE)
+        .serve("127.0.0.2", TEST_ZONE)
+        .serve("127.0.0.3", NET_ZONE)
+        .serve("127.0.0.4", &example_zone)
+        .serve("127.0.0.5", HOSTING_ZONE)
+        .serve("127.0.0.6", GLUELESS_ZONE)
+}
+
+#[cfg(test)]
+#[test]
+fn hierarchy_follows_referrals() {
+    let resolver = start_hierarchy().resolver();
+    let res = resolver.resolve("www.example.test", Type::A);
+    assert_eq!(res, Ok("192.0.2.1".to_string()));
+}
+
+#[cfg(test)]
+#[test]
+fn hierarchy_glueless_delegation() {
+    let resolver = start_hierarchy().resolver();
+    let res = resolver.resolve("www.glueless.test", Type::A);
+    assert_eq!(res, Ok("192.0.2.2".to_string()));
+}
+
+#[cfg(test)]
+#[test]
+fn hierarchy_cname_chain_across_zones() {
+    let resolver = start_hierarchy().resolver();
+    let question = DnsQuestion::new("alias.example.test", Type::A, Class::CLASS_IN);
+    let response = resolver
+        .resolve_packet(DnsPacket::packet_from_question(question))
+        .unwrap();
+    let answers: Vec<String> = response.answers.iter().map(|r| r.to_string()).collect();
+    assert_eq!(
+        answers,
+        vec![
+            "alias.example.test 3600 IN CNAME shop.glueless.test",
+            "shop.glueless.test 3600 IN CNAME web.hosting.net",
+            "web.hosting.net 3600 IN A 192.0.2.3",
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn hierarchy_nxdomain() {
+    let resolver = start_hierarchy().resolver();
+    let question = DnsQuestion::new("missing.example.test", Type::A, Class::CLASS_IN);
+    let response = resolver
+        .resolve_packet(DnsPacket::packet_from_question(question))
+        .unwrap();
+    assert_eq!(response.header.rcode(), HeaderFlags::RCODE_NAME_ERR as u16);
+    assert!(response.answers.is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn hierarchy_truncated_response_retried_over_tcp() {
+    let resolver = start_hierarchy().resolver();
+    // the truncated UDP response has no answers, so this one came over TCP
+    let res = resolver.resolve("big.example.test", Type::A);
+    assert_eq!(res, Ok("192.0.2.1".to_string()));
+}
+
+/// Answer every query on a loopback socket with `rcode`, plus an A record when `ip` is given
+fn spawn_upstream(rcode: u16, ip: Option<&'static str>) -> SocketAddr {
+    spawn_responder(move |packet| {
+        let mut packet = match ip {
+            Some(ip) => answer(packet, Rdata::A(ip.to_string())),
+            None => packet,
+        };
+        packet.header.flags |= HeaderFlags::QR_RESPONSE as u16 | rcode;
+        packet
+    })
+}
+
+/// Answer every query with the response built by `respond`
+fn spawn_responder(respond: impl Fn(DnsPacket) -> DnsPacket + Send + 'static) -> SocketAddr {
+    spawn_responder_on("127.0.0.1:0", respond)
+}
+
+/// Answer every query sent to `bind_addr` with the response built by `respond`
+fn spawn_responder_on(
+    bind_addr: &str,
+    respond: impl Fn(DnsPacket) -> DnsPacket + Send + 'static,
+) -> SocketAddr {
+    let socket = UdpSocket::bind(bind_addr).unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || loop {
+        let mut buf = [0u8; 512];
+        let (_n_bytes, src_addr) = socket.recv_from(&mut buf).unwrap();
+        let query = DnsPacket::from_bytes(&buf).unwrap();
+        let response = respond(query).to_bytes().unwrap();
+        socket.send_to(&response, src_addr).unwrap();
+    });
+    addr
+}
+
+#[cfg(test)]
+#[test]
+fn forward_to_upstream() {
+    let upstream = spawn_upstream(0, Some("10.0.0.1"));
+    let resolver = Resolver::default().with_upstream_mode(UpstreamMode::Forward(vec![upstream]));
+    let res = resolver.resolve("example.com", Type::A);
+    assert_eq!(res, Ok("10.0.0.1".to_string()));
+}
+
+#[cfg(test)]
+#[test]
+fn forward_failover() {
+    let failing = spawn_upstream(HeaderFlags::RCODE_SERVER_ERR as u16, None);
+    let working = spawn_upstream(0, Some("10.0.0.2"));
+    let resolver =
+        Resolver::default().with_upstream_mode(UpstreamMode::Forward(vec![failing, working]));
+    let res = resolver.resolve("example.com", Type::A);
+    assert_eq!(res, Ok("10.0.0.2".to_string()));
+}
+
+#[cfg(test)]
+#[test]
+fn forward_all_upstreams_fail() {
+    let failing = spawn_upstream(HeaderFlags::RCODE_REFUSED as u16, None);
+    let resolver = Resolver::default().with_upstream_mode(UpstreamMode::Forward(vec![failing]));
+    let res = resolver.resolve("example.com", Type::A);
+    assert!(res.is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn forward_zone_longest_suffix() {
+    let office = spawn_upstream(0, Some("10.0.0.3"));
+    let lab = spawn_upstream(0, Some("10.0.0.4"));
+    let resolver = Resolver::default()
+        .with_forward_zone("corp.internal", vec![office])
+        .with_forward_zone("lab.corp.internal", vec![lab]);
+    let res = resolver.resolve("printer.corp.internal", Type::A);
+    assert_eq!(res, Ok("10.0.0.3".to_string()));
+    let res = resolver.resolve("bench.lab.corp.internal", Type::A);
+    assert_eq!(res, Ok("10.0.0.4".to_string()));
+}
+
+#[cfg(test)]
+#[test]
+fn resolve_from_mock_root() {
+    let root = spawn_upstream(0, Some("10.0.0.5"));
+    let resolver = Resolver::default().with_root_hints(RootHints::from_addrs(vec![root]).unwrap());
+    // the mock root can't answer a priming query, so the hints are kept
+    assert!(resolver.prime().is_err());
+    let res = resolver.resolve("example.com", Type::A);
+    assert_eq!(res, Ok("10.0.0.5".to_string()));
+}
+
+/// Receive queries on a loopback socket without ever answering
+fn spawn_silent_upstream() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || loop {
+        let mut buf = [0u8; 512];
+        let _ = socket.recv_from(&mut buf);
+    });
+    addr
+}
+
+fn short_timeouts() -> Timeouts {
+    Timeouts {
+        query: Duration::from_millis(50),
+        retries: 2,
+        resolution: Duration::from_millis(120),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn forward_failover_on_timeout() {
+    let silent = spawn_silent_upstream();
+    let working = spawn_upstream(0, Some("10.0.0.6"));
+    let resolver = Resolver::default()
+        .with_timeouts(short_timeouts())
+        .with_upstream_mode(UpstreamMode::Forward(vec![silent, working]));
+    let res = resolver.resolve("example.com", Type::A);
+    assert_eq!(res, Ok("10.0.0.6".to_string()));
+}
+
+#[cfg(test)]
+#[test]
+fn resolution_deadline_exceeded() {
+    let silent = spawn_silent_upstream();
+    let resolver = Resolver::default()
+        .with_timeouts(short_timeouts())
+        .with_root_hints(RootHints::from_addrs(vec![silent]).unwrap());
+    let res = resolver.resolve("example.com", Type::A);
+    assert_eq!(
+        res,
+        Err(DnsError::DeadlineError(
+            "Resolution deadline exceeded".to_string()
+        ))
+    );
+}
+
+/// Answer each query correctly, but first send forged answers from another port and with the
+/// wrong transaction ID
+fn spawn_spoofed_upstream(ip: &'static str, forged: &'static str) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || loop {
+        let mut buf = [0u8; 512];
+        let (_n_bytes, src_addr) = socket.recv_from(&mut buf).unwrap();
+        let packet = DnsPacket::from_bytes(&buf).unwrap();
+        let mut forged_packet = answer(packet.clone(), Rdata::A(forged.to_string()));
+        let wrong_port = forged_packet.clone().to_bytes().unwrap();
+        spoofer.send_to(&wrong_port, src_addr).unwrap();
+        forged_packet.header.id = forged_packet.header.id.wrapping_add(1);
+        let wrong_id = forged_packet.to_bytes().unwrap();
+        socket.send_to(&wrong_id, src_addr).unwrap();
+        let packet = answer(packet, Rdata::A(ip.to_string()));
+        socket
+            .send_to(&packet.to_bytes().unwrap(), src_addr)
+            .unwrap();
+    });
+    addr
+}
+
+#[cfg(test)]
+#[test]
+fn discard_forged_responses() {
+    let upstream = spawn_spoofed_upstream("10.0.0.7", "192.0.2.66");
+    let resolver = Resolver::default().with_upstream_mode(UpstreamMode::Forward(vec![upstream]));
+    let res = resolver.resolve("example.com", Type::A);
+    assert_eq!(res, Ok("10.0.0.7".to_string()));
+}
+
+/// Answer with an A record, but lowercase the echoed question like some middleboxes do
+fn lowercase_responder(packet: DnsPacket) -> DnsPacket {
+    let mut packet = answer(packet, Rdata::A("10.0.0.8".to_string()));
+    packet.questions[0].name = packet.questions[0].name.to_lowercase();
+    packet
+}
+
+// Long enough that a randomized name is never all lowercase in practice
+const MIXED_CASE_NAME: &str = "case-randomization-test.example.com";
+
+#[cfg(test)]
+#[test]
+fn case_randomization_echoed() {
+    let upstream = spawn_upstream(0, Some("10.0.0.8"));
+    let resolver = Resolver::default()
+        .with_case_randomization(HashSet::new())
+        .with_upstream_mode(UpstreamMode::Forward(vec![upstream]));
+    let res = resolver.resolve(MIXED_CASE_NAME, Type::A);
+    assert_eq!(res, Ok("10.0.0.8".to_string()));
+}
+
+#[cfg(test)]
+#[test]
+fn case_randomization_rejects_mangled_case() {
+    let upstream = spawn_responder(lowercase_responder);
+    let resolver = Resolver::default()
+        .with_timeouts(short_timeouts())
+        .with_case_randomization(HashSet::new())
+        .with_upstream_mode(UpstreamMode::Forward(vec![upstream]));
+    let res = resolver.resolve(MIXED_CASE_NAME, Type::A);
+    assert!(res.is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn case_randomization_exempt_server() {
+    let upstream = spawn_responder(lowercase_responder);
+    let resolver = Resolver::default()
+        .with_case_randomization(HashSet::from([upstream.ip()]))
+        .with_upstream_mode(UpstreamMode::Forward(vec![upstream]));
+    let res = resolver.resolve(MIXED_CASE_NAME, Type::A);
+    assert_eq!(res, Ok("10.0.0.8".to_string()));
+}
+
+#[cfg(test)]
+#[test]
+fn qname_minimisation_reveals_one_label_at_a_time() {
+    let seen = Arc::new(Mutex::new(vec![]));
+    let seen_by_root = seen.clone();
+    // a root which answers everything itself, so each minimised name exists and isn't delegated
+    let root = spawn_responder(move |packet| {
+        let question = packet.questions[0].clone();
+        seen_by_root
+            .lock()
+            .unwrap()
+            .push((question.name, question.qtype));
+        answer(packet, Rdata::A("10.0.0.9".to_string()))
+    });
+    let resolver = Resolver::default()
+        .with_qname_minimisation(true)
+        .with_root_hints(RootHints::from_addrs(vec![root]).unwrap());
+    let res = resolver.resolve("www.example.com", Type::A);
+    assert_eq!(res, Ok("10.0.0.9".to_string()));
+    let expected = vec![
+        ("com".to_string(), Type::A),
+        ("example.com".to_string(), Type::A),
+        ("www.example.com".to_string(), Type::A),
+    ];
+    assert_eq!(*seen.lock().unwrap(), expected);
+}
+
+/// Answer every query with a CNAME to the name chosen by `target`
+fn spawn_cname_responder(target: fn(&str) -> String) -> SocketAddr {
+    spawn_responder(move |packet| {
+        let cname = target(&packet.questions[0].name);
+        answer(packet, Rdata::CNAME(cname))
+    })
+}
+
+#[cfg(test)]
+#[test]
+fn cname_loop_detected() {
+    let root = spawn_cname_responder(|name| {
+        if name.starts_with("a.") {
+            "b.example.com".to_string()
+        } else {
+            "a.example.com".to_string()
+        }
+    });
+    let resolver = Resolver::default().with_root_hints(RootHints::from_addrs(vec![root]).unwrap());
+    let res = resolver.resolve("a.example.com", Type::A);
+    assert_eq!(
+        res,
+        Err(DnsError::LimitError(
+            "CNAME loop detected at a.example.com".to_string()
+        ))
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn cname_chain_limited() {
+    // every name points one label deeper, so the chain never ends
+    let root = spawn_cname_responder(|name| format!("x.{}", name));
+    let resolver = Resolver::default()
+        .with_limits(Limits {
+            cname_chain: 3,
+            ..Limits::default()
+        })
+        .with_root_hints(RootHints::from_addrs(vec![root]).unwrap());
+    let res = resolver.resolve("example.com", Type::A);
+    assert_eq!(
+        res,
+        Err(DnsError::LimitError(
+            "CNAME chain for example.com is longer than 3".to_string()
+        ))
+    );
+}
+
+/// Answer every query with an AAAA record
+fn aaaa_responder(packet: DnsPacket) -> DnsPacket {
+    answer(packet, Rdata::AAAA("2001:db8::1".to_string()))
+}
+
+#[cfg(test)]
+#[test]
+fn resolve_aaaa_over_ipv6() {
+    let root = spawn_responder_on("[::1]:0", aaaa_responder);
+    let resolver = Resolver::default()
+        .with_ip_preference(IpPreference::Ipv6Only)
+        .with_root_hints(RootHints::from_addrs(vec![root]).unwrap());
+    let res = resolver.resolve("example.com", Type::AAAA);
+    assert_eq!(res, Ok("2001:db8::1".to_string()));
+}
+
+#[cfg(test)]
+#[test]
+fn ipv4_only_skips_ipv6_nameservers() {
+    let root = spawn_responder_on("[::1]:0", aaaa_responder);
+    let resolver = Resolver::default()
+        .with_ip_preference(IpPreference::Ipv4Only)
+        .with_root_hints(RootHints::from_addrs(vec![root]).unwrap());
+    let res = resolver.resolve("example.com", Type::AAAA);
+    assert_eq!(
+        res,
+        Err(DnsError::ResolveError(
+            "No nameservers to query".to_string()
+        ))
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn trace_records_each_step() {
+    let root = spawn_responder(|packet| {
+        let rdata = if packet.questions[0].name.starts_with("alias.") {
+            Rdata::CNAME("www.example.com".to_string())
+        } else {
+            Rdata::A("10.0.0.10".to_string())
+        };
+        answer(packet, rdata)
+    });
+    let resolver = Resolver::default().with_root_hints(RootHints::from_addrs(vec![root]).unwrap());
+    let trace = resolver.trace("alias.example.com", Type::A);
+    assert!(trace.result.is_ok());
+    let events: Vec<&TraceEvent> = trace.steps.iter().map(|step| &step.event).collect();
+    assert!(matches!(
+        events[..],
+        [
+            TraceEvent::Query { server: first, .. },
+            TraceEvent::Cname { .. },
+            TraceEvent::Query { server: second, .. },
+        ] if *first == root && *second == root
+    ));
+    // the CNAME target was cached along the way
+    let trace = resolver.trace("www.example.com", Type::A);
+    assert!(matches!(
+        trace.steps[..],
+        [TraceStep {
+            event: TraceEvent::CacheHit { .. },
+            ..
+        }]
+    ));
+}
+
+/// Send an A query for `name` to `server` and return the answered address
+fn query_server(server: SocketAddr, name: &str) -> String {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let question = DnsQuestion::new(name, Type::A, Class::CLASS_IN);
+    let query = DnsPacket::packet_from_question(question)
+        .to_bytes()
+        .unwrap();
+    socket.send_to(&query, server).unwrap();
+    let mut buf = [0u8; 512];
+    let (n_bytes, _) = socket.recv_from(&mut buf).unwrap();
+    let response = DnsPacket::from_bytes(&buf[..n_bytes]).unwrap();
+    match &response.answers[0].rdata {
+        Rdata::A(ip) => ip.clone(),
+        rdata => panic!("unexpected answer {:?}", rdata),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn server_answers_while_a_query_is_slow() {
+    let slow = spawn_responder(|packet| {
+        thread::sleep(Duration::from_millis(500));
+        lowercase_responder(packet)
+    });
+    let fast = spawn_upstream(0, Some("10.0.0.11"));
+    let resolver = Resolver::default()
+        .with_forward_zone("slow.test", vec![slow])
+        .with_forward_zone("fast.test", vec![fast]);
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = socket.local_addr().unwrap();
+    thread::spawn(move || Server::new(Arc::new(resolver)).run(socket));
+    let slow_query = thread::spawn(move || query_server(server_addr, "www.slow.test"));
+    thread::sleep(Duration::from_millis(50));
+    let started = Instant::now();
+    assert_eq!(query_server(server_addr, "www.fast.test"), "10.0.0.11");
+    assert!(started.elapsed() < Duration::from_millis(400));
+    assert_eq!(slow_query.join().unwrap(), "10.0.0.8");
+}
+
+#[cfg(test)]
+#[test]
+fn server_shutdown_drains_queries_in_flight() {
+    let slow = spawn_responder(|packet| {
+        thread::sleep(Duration::from_millis(300));
+        lowercase_responder(packet)
+    });
+    let resolver = Resolver::default().with_upstream_mode(UpstreamMode::Forward(vec![slow]));
+    let listeners = Listeners::bind(&[Listener::new("127.0.0.1:0".parse().unwrap())]).unwrap();
+    let server_addr = listeners.local_addrs().unwrap()[0];
+    let server = Server::new(Arc::new(resolver));
+    let handle = server.control();
+    let serving = thread::spawn(move || server.serve(listeners));
+    let slow_query = thread::spawn(move || query_server(server_addr, "www.slow.test"));
+    thread::sleep(Duration::from_millis(50));
+    handle.shutdown();
+    assert_eq!(slow_query.join().unwrap(), "10.0.0.8");
+    serving.join().unwrap().unwrap();
+    let options = QueryOptions {
+        recursion_desired: true,
+        randomize_case: false,
+        timeout: Duration::from_millis(200),
+    };
+    let question = DnsQuestion::new("www.slow.test", Type::A, Class::CLASS_IN);
+    assert!(DnsPacket::send_query_tcp(server_addr, &question, options).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn server_reload_keeps_sockets_open() {
+    let resolver_with = |record: &str| {
+        let mut local_records = LocalRecords::new();
+        local_records.add(record.parse().unwrap());
+        Arc::new(Resolver::default().with_local_records(local_records))
+    };
+    let listeners = Listeners::bind(&[Listener::new("127.0.0.1:0".parse().unwrap())]).unwrap();
+    let server_addr = listeners.local_addrs().unwrap()[0];
+    let server = Server::new(resolver_with("router.lan 300 IN A 192.168.1.1"));
+    let handle = server.control();
+    thread::spawn(move || server.serve(listeners));
+    assert_eq!(query_server(server_addr, "router.lan"), "192.168.1.1");
+    let mut stream = TcpStream::connect(server_addr).unwrap();
+    let response = exchange_tcp(&mut stream, "router.lan").unwrap();
+    assert_eq!(response.answers.len(), 1);
+
+    let reloaded = resolver_with("router.lan 300 IN A 192.168.1.2");
+    handle.reload(reloaded.clone(), Acl::default(), RateLimits::default());
+    assert!(Arc::ptr_eq(&handle.resolver(), &reloaded));
+    assert_eq!(query_server(server_addr, "router.lan"), "192.168.1.2");
+
+    handle.reload(reloaded, Acl::new(AclAction::Refuse), RateLimits::default());
+    let options = QueryOptions {
+        recursion_desired: true,
+        randomize_case: false,
+        timeout: Duration::from_millis(500),
+    };
+    let question = DnsQuestion::new("router.lan", Type::A, Class::CLASS_IN);
+    let refused = DnsPacket::send_query(server_addr, &question, options).unwrap();
+    assert_eq!(refused.header.rcode(), HeaderFlags::RCODE_REFUSED as u16);
+    // The new ACL also applies to connections opened before the reload
+    let refused = exchange_tcp(&mut stream, "router.lan").unwrap();
+    assert_eq!(refused.header.rcode(), HeaderFlags::RCODE_REFUSED as u16);
+
+    let reloaded = resolver_with("router.lan 300 IN A 192.168.1.2");
+    handle.reload(reloaded, Acl::new(AclAction::Deny), RateLimits::default());
+    assert!(exchange_tcp(&mut stream, "router.lan").is_none());
+}
+
+/// Send a query for `name` on an open TCP connection, returning None if it's closed instead of
+/// answered
+fn exchange_tcp(stream: &mut TcpStream, name: &str) -> Option<DnsPacket> {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let question = DnsQuestion::new(name, Type::A, Class::CLASS_IN);
+    let query = DnsPacket::packet_from_question(question)
+        .to_bytes()
+        .unwrap();
+    stream
+        .write_all(&(query.len() as u16).to_be_bytes())
+        .unwrap();
+    stream.write_all(&query).unwrap();
+    let mut length = [0u8; 2];
+    match stream.read_exact(&mut length) {
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return None,
+        result => result.unwrap(),
+    }
+    let mut buf = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut buf).unwrap();
+    Some(DnsPacket::from_bytes(&buf).unwrap())
+}
+
+#[cfg(test)]
+#[test]
+fn server_listens_on_multiple_addresses() {
+    let mut local_records = LocalRecords::new();
+    local_records.add("router.lan 300 IN A 192.168.1.1".parse().unwrap());
+    for i in 1..=40 {
+        local_records.add(format!("big.lan 300 IN A 10.0.0.{}", i).parse().unwrap());
+    }
+    let resolver = Resolver::default().with_local_records(local_records);
+    let v6_listener = Listener {
+        v6_only: true,
+        ..Listener::new("[::1]:0".parse().unwrap())
+    };
+    let listeners =
+        Listeners::bind(&[Listener::new("127.0.0.1:0".parse().unwrap()), v6_listener]).unwrap();
+    let addrs = listeners.local_addrs().unwrap();
+    thread::spawn(move || Server::new(Arc::new(resolver)).serve(listeners));
+    let options = QueryOptions {
+        recursion_desired: true,
+        randomize_case: false,
+        timeout: Duration::from_secs(5),
+    };
+
+    let router = DnsQuestion::new("router.lan", Type::A, Class::CLASS_IN);
+    for addr in &addrs {
+        let udp = DnsPacket::send_query(*addr, &router, options).unwrap();
+        let tcp = DnsPacket::send_query_tcp(*addr, &router, options).unwrap();
+        assert_eq!(udp.answers, tcp.answers);
+        assert_eq!(udp.answers.len(), 1);
+    }
+
+    // Too big for a datagram, so only TCP gets the answers
+    let big = DnsQuestion::new("big.lan", Type::A, Class::CLASS_IN);
+    let udp = DnsPacket::send_query(addrs[0], &big, options).unwrap();
+    assert!(udp.is_truncated());
+    assert_eq!(udp.answers, vec![]);
+    let tcp = DnsPacket::send_query_tcp(addrs[0], &big, options).unwrap();
+    assert!(!tcp.is_truncated());
+    assert_eq!(tcp.answers.len(), 40);
+}
+
+#[cfg(test)]
+#[test]
+fn server_applies_client_acl() {
+    let mut local_records = LocalRecords::new();
+    local_records.add("router.lan 300 IN A 192.168.1.1".parse().unwrap());
+    let upstream = spawn_upstream(0, Some("10.0.0.14"));
+    let resolver = Resolver::default()
+        .with_local_records(local_records)
+        .with_upstream_mode(UpstreamMode::Forward(vec![upstream]));
+    let acl = Acl::new(AclAction::Refuse)
+        .with_rule("127.0.0.1".parse().unwrap(), AclAction::AllowLocalOnly)
+        .with_rule("::1".parse().unwrap(), AclAction::Deny);
+    // Dual-stack, so IPv4 clients arrive as IPv4-mapped addresses and must still match
+    let listeners = Listeners::bind(&[Listener::new("[::]:0".parse().unwrap())]).unwrap();
+    let port = listeners.local_addrs().unwrap()[0].port();
+    thread::spawn(move || {
+        Server::new(Arc::new(resolver))
+            .with_acl(acl)
+            .serve(listeners)
+    });
+    let options = QueryOptions {
+        recursion_desired: true,
+        randomize_case: false,
+        timeout: Duration::from_millis(500),
+    };
+    let v4 = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+    let v6 = SocketAddr::new("::1".parse().unwrap(), port);
+
+    let router = DnsQuestion::new("router.lan", Type::A, Class::CLASS_IN);
+    let local = DnsPacket::send_query(v4, &router, options).unwrap();
+    assert_eq!(local.answers.len(), 1);
+    let example = DnsQuestion::new("example.com", Type::A, Class::CLASS_IN);
+    for refused in [
+        DnsPacket::send_query(v4, &example, options).unwrap(),
+        DnsPacket::send_query_tcp(v4, &example, options).unwrap(),
+    ] {
+        assert_eq!(refused.header.rcode(), HeaderFlags::RCODE_REFUSED as u16);
+        assert_eq!(refused.answers, vec![]);
+    }
+
+    assert!(DnsPacket::send_query(v6, &router, options).is_err());
+    assert!(DnsPacket::send_query_tcp(v6, &router, options).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn server_rate_limits_by_range() {
+    let mut local_records = LocalRecords::new();
+    local_records.add("router.lan 300 IN A 192.168.1.1".parse().unwrap());
+    let resolver = Resolver::default().with_local_records(local_records);
+    let query_limit = ClientLimits {
+        queries_per_second: Some(1),
+        ..ClientLimits::default()
+    };
+    let response_limit = ClientLimits {
+        responses_per_second: Some(1),
+        slip: 1,
+        ..ClientLimits::default()
+    };
+    let rate_limits = RateLimits::default()
+        .with_range("127.0.0.1".parse().unwrap(), query_limit)
+        .with_range("::1".parse().unwrap(), response_limit);
+    let listeners = Listeners::bind(&[Listener::new("[::]:0".parse().unwrap())]).unwrap();
+    let port = listeners.local_addrs().unwrap()[0].port();
+    thread::spawn(move || {
+        Server::new(Arc::new(resolver))
+            .with_rate_limits(rate_limits)
+            .serve(listeners)
+    });
+    let options = QueryOptions {
+        recursion_desired: true,
+        randomize_case: false,
+        timeout: Duration::from_millis(500),
+    };
+    let router = DnsQuestion::new("router.lan", Type::A, Class::CLASS_IN);
+
+    let v4 = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+    assert!(DnsPacket::send_query(v4, &router, options).is_ok());
+    assert!(DnsPacket::send_query(v4, &router, options).is_err());
+
+    // Over the response limit with a slip of 1, every response is truncated instead of dropped
+    let v6 = SocketAddr::new("::1".parse().unwrap(), port);
+    let first = DnsPacket::send_query(v6, &router, options).unwrap();
+    assert_eq!(first.answers.len(), 1);
+    let second = DnsPacket::send_query(v6, &router, options).unwrap();
+    assert!(second.is_truncated());
+    assert_eq!(second.answers, vec![]);
+    let tcp = DnsPacket::send_query_tcp(v6, &router, options).unwrap();
+    assert_eq!(tcp.answers.len(), 1);
+}
+
+#[cfg(test)]
+#[test]
+fn server_refuses_rate_limited_tcp_queries() {
+    let mut local_records = LocalRecords::new();
+    local_records.add("router.lan 300 IN A 192.168.1.1".parse().unwrap());
+    let resolver = Resolver::default().with_local_records(local_records);
+    let query_limit = ClientLimits {
+        queries_per_second: Some(1),
+        ..ClientLimits::default()
+    };
+    let rate_limits = RateLimits::default().with_range("127.0.0.1".parse().unwrap(), query_limit);
+    let listeners = Listeners::bind(&[Listener::new("127.0.0.1:0".parse().unwrap())]).unwrap();
+    let addr = listeners.local_addrs().unwrap()[0];
+    thread::spawn(move || {
+        Server::new(Arc::new(resolver))
+            .with_rate_limits(rate_limits)
+            .serve(listeners)
+    });
+    let options = QueryOptions {
+        recursion_desired: true,
+        randomize_case: false,
+        timeout: Duration::from_secs(5),
+    };
+    let router = DnsQuestion::new("router.lan", Type::A, Class::CLASS_IN);
+
+    let first = DnsPacket::send_query_tcp(addr, &router, options).unwrap();
+    assert_eq!(first.answers.len(), 1);
+    let start = Instant::now();
+    let refused = DnsPacket::send_query_tcp(addr, &router, options).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(refused.header.rcode(), HeaderFlags::RCODE_REFUSED as u16);
+    assert_eq!(refused.answers, vec![]);
+}
+
+/// Answer queries over UDP with truncated, empty responses, and over TCP on the same port with
+/// an A record
+fn spawn_truncating_upstream(ip: &'static str) -> SocketAddr {
+    let addr = spawn_responder(|mut packet| {
+        packet.header.flags |= HeaderFlags::QR_RESPONSE as u16 | HeaderFlags::TC_TRUNCATED as u16;
+        packet
+    });
+    let listener = TcpListener::bind(addr).unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut length = [0u8; 2];
+            stream.read_exact(&mut length).unwrap();
+            let mut buf = vec![0u8; u16::from_be_bytes(length) as usize];
+            stream.read_exact(&mut buf).unwrap();
+            let packet = DnsPacket::from_bytes(&buf).unwrap();
+            let response = answer(packet, Rdata::A(ip.to_string())).to_bytes().unwrap();
+            let length = (response.len() as u16).to_be_bytes();
+            stream
+                .write_all(&[&length[..], &response].concat())
+                .unwrap();
+        }
+    });
+    addr
+}
+
+#[cfg(test)]
+#[test]
+fn truncated_response_retried_over_tcp() {
+    let upstream = spawn_truncating_upstream("10.0.0.12");
+    let resolver = Resolver::default().with_upstream_mode(UpstreamMode::Forward(vec![upstream]));
+    let res = resolver.resolve("example.com", Type::A);
+    assert_eq!(res, Ok("10.0.0.12".to_string()));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn async_forward_to_upstream() {
+    let upstream = spawn_upstream(0, Some("10.0.0.13"));
+    let resolver = AsyncResolver::new(
+        Resolver::default().with_upstream_mode(UpstreamMode::Forward(vec![upstream])),
+    );
+    let res = resolver.resolve("example.com", Type::A).await;
+    assert_eq!(res, Ok("10.0.0.13".to_string()));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn async_truncated_response_retried_over_tcp() {
+    let upstream = spawn_truncating_upstream("10.0.0.14");
+    let resolver = AsyncResolver::new(
+        Resolver::default().with_upstream_mode(UpstreamMode::Forward(vec![upstream])),
+    );
+    let res = resolver.resolve("example.com", Type::A).await;
+    assert_eq!(res, Ok("10.0.0.14".to_string()));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn async_lookup_cancelled() {
+    let silent = spawn_silent_upstream();
+    let working = spawn_upstream(0, Some("10.0.0.15"));
+    let resolver = AsyncResolver::new(
+        Resolver::default()
+            .with_forward_zone("silent.test", vec![silent])
+            .with_forward_zone("working.test", vec![working]),
+    );
+    let lookup = resolver.resolve("www.silent.test", Type::A);
+    let cancelled = tokio::time::timeout(Duration::from_millis(50), lookup).await;
+    assert!(cancelled.is_err());
+    let res = resolver.resolve("www.working.test", Type::A).await;
+    assert_eq!(res, Ok("10.0.0.15".to_string()));
+}
+
+#[cfg(test)]
+#[test]
+fn identical_queries_coalesced() {
+    let queries = Arc::new(Mutex::new(0));
+    let counted = queries.clone();
+    let upstream = spawn_responder(move |packet| {
+        *counted.lock().unwrap() += 1;
+        thread::sleep(Duration::from_millis(200));
+        lowercase_responder(packet)
+    });
+    let resolver =
+        Arc::new(Resolver::default().with_upstream_mode(UpstreamMode::Forward(vec![upstream])));
+    let lookups: Vec<_> = (0..4)
+        .map(|_| {
+            let resolver = resolver.clone();
+            thread::spawn(move || resolver.resolve("www.example.com", Type::A))
+        })
+        .collect();
+    for lookup in lookups {
+        assert_eq!(lookup.join().unwrap(), Ok("10.0.0.8".to_string()));
+    }
+    assert_eq!(*queries.lock().unwrap(), 1);
+}
+
+/// Answer with a referral to `zone`, served by `nameserver` at the glue address `ip`
+fn referral(
+    zone: &'static str,
+    nameserver: &'static str,
+    ip: &'static str,
+) -> impl Fn(DnsPacket) -> Option<DnsPacket> {
+    move |mut packet| {
+        packet.header.flags |= HeaderFlags::QR_RESPONSE as u16;
+        packet.header.num_authorities = 1;
+        packet.header.num_additionals = 1;
+        packet.authorities.push(DnsRecord {
+            name: zone.to_string(),
+            class: Class::CLASS_IN,
+            ttl: 3600,
+            rdata: Rdata::NS(nameserver.to_string()),
+        });
+        packet.additionals.push(DnsRecord {
+            name: nameserver.to_string(),
+            class: Class::CLASS_IN,
+            ttl: 3600,
+            rdata: Rdata::A(ip.to_string()),
+        });
+        Some(packet)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn recursion_over_mock_transport() {
+    let root: SocketAddr = "198.51.100.1:53".parse().unwrap();
+    let tld: SocketAddr = "192.0.2.10:53".parse().unwrap();
+    let leaf: SocketAddr = "192.0.2.20:53".parse().unwrap();
+    let transport = Arc::new(
+        MockTransport::new()
+            .with_server(root, referral("com", "a.gtld-servers.net", "192.0.2.10"))
+            .with_server(
+                tld,
+                referral("example.com", "ns1.example.com", "192.0.2.20"),
+            )
+            .with_server(leaf, |packet| Some(lowercase_responder(packet))),
+    );
+    let resolver = Resolver::default()
+        .with_root_hints(RootHints::from_addrs(vec![root]).unwrap())
+        .with_transport(transport.clone());
+    let res = resolver.resolve("www.example.com", Type::A);
+    assert_eq!(res, Ok("10.0.0.8".to_string()));
+    let servers: Vec<_> = transport
+        .queries()
+        .into_iter()
+        .map(|(server, _)| server)
+        .collect();
+    assert_eq!(servers, vec![root, tld, leaf]);
+}
+
+#[cfg(test)]
+#[test]
+fn config_blocking_behaviour() {
+    let blocklist = HashSet::from(["ads.example.com".to_string()]);
+    let resolver = ResolverConfig::new()
+        .with_blocklist(blocklist.clone())
+        .with_blocking(Blocking {
+            response: BlockResponse::NullAddress,
+            ttl: 60,
+        })
+        .build()
+        .unwrap();
+    let question = DnsQuestion::new("ads.example.com", Type::AAAA, Class::CLASS_IN);
+    let response = resolver
+        .resolve_packet(DnsPacket::packet_from_question(question))
+        .unwrap();
+    let answers: Vec<String> = response.answers.iter().map(|r| r.to_string()).collect();
+    assert_eq!(answers, vec!["ads.example.com 60 IN AAAA ::"]);
+
+    let resolver = ResolverConfig::new()
+        .with_blocklist(blocklist)
+        .with_blocking(Blocking {
+            response: BlockResponse::NxDomain,
+            ..Blocking::default()
+        })
+        .build()
+        .unwrap();
+    let question = DnsQuestion::new("ads.example.com", Type::A, Class::CLASS_IN);
+    let response = resolver
+        .resolve_packet(DnsPacket::packet_from_question(question))
+        .unwrap();
+    assert_eq!(response.header.rcode(), HeaderFlags::RCODE_NAME_ERR as u16);
+    assert!(response.answers.is_empty());
+}
 
 #[cfg(test)]
 #[test]
-fn resolve_facebook() {
-    let domain_name = "www.facebook.com";
-    let mut resolver = Resolver::default();
-    let res = resolver.resolve(domain_name, Type::A);
-    assert!(res.is_ok())
+fn config_rejects_invalid_options() {
+    let res = ResolverConfig::new()
+        .with_upstream_mode(UpstreamMode::Forward(vec![]))
+        .build();
+    assert!(matches!(res, Err(DnsError::ConfigError(_))));
 }
 
 #[cfg(test)]
 #[test]
-fn resolve_twitter() {
-    let domain_name = "twitter.com";
-    let mut resolver = Resolver::default();
-    let res = resolver.resolve(domain_name, Type::A);
-    assert!(res.is_ok())
+fn local_records_and_allowlist() {
+    let upstream = spawn_upstream(0, Some("10.0.0.9"));
+    let mut local_records = LocalRecords::new();
+    local_records.add("router.lan 300 IN A 192.168.1.1".parse().unwrap());
+    let resolver = ResolverConfig::new()
+        .with_upstream_mode(UpstreamMode::Forward(vec![upstream]))
+        .with_local_records(local_records)
+        .with_blocklist(HashSet::from([
+            "ads.example.com".to_string(),
+            "cdn.example.com".to_string(),
+        ]))
+        .with_allowlist(HashSet::from(["cdn.example.com".to_string()]))
+        .build()
+        .unwrap();
+    assert_eq!(
+        resolver.resolve("router.lan", Type::A),
+        Ok("192.168.1.1".to_string())
+    );
+    assert_eq!(
+        resolver.resolve("ads.example.com", Type::A),
+        Ok("0.0.0.0".to_string())
+    );
+    assert_eq!(
+        resolver.resolve("cdn.example.com", Type::A),
+        Ok("10.0.0.9".to_string())
+    );
 }